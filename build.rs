@@ -0,0 +1,34 @@
+use std::env;
+
+// the parts of nginx newer than the oldest version built for (see compile.yml),
+// as (cfg, the first version with it)
+const FEATURES: [(&str, (u32, u32, u32)); 2] = [
+    // ngx_proxy_protocol_get_tlv(), for strict_sni_proxy_protocol
+    ("ngx_proxy_protocol_tlv", (1, 23, 2)),
+    // HTTP/3, and ngx_http_conf_port_t.type telling its UDP ports from the TCP ones
+    ("ngx_quic", (1, 25, 0)),
+];
+
+// the version ngx-sys builds without NGX_VERSION
+const DEFAULT_NGX_VERSION: &str = "1.24.0";
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=NGX_VERSION");
+    let version = env::var("NGX_VERSION").unwrap_or_else(|_| DEFAULT_NGX_VERSION.to_string());
+    let Some(version) = parse_version(&version) else {
+        panic!("NGX_VERSION is no nginx version: {}", version);
+    };
+    for (cfg, since) in FEATURES {
+        println!("cargo:rustc-check-cfg=cfg({})", cfg);
+        if version >= since {
+            println!("cargo:rustc-cfg={}", cfg);
+        }
+    }
+}
+
+// major.minor.patch
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut iter = version.trim().split('.').map(|n| n.parse().ok());
+    let version = (iter.next()??, iter.next()??, iter.next()??);
+    iter.next().is_none().then_some(version)
+}
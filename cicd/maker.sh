@@ -40,6 +40,8 @@ cargo build --target "$triple" --release
 # ls -l target/$triple/release
 
 echo "[TEST]"
+# nghttp is installed by compile.yml
+export STRICT_SNI_TEST_NGHTTP=1
 cargo test --target "$triple" --release
 
 # # test
@@ -0,0 +1,183 @@
+use core::cell::Cell;
+use core::mem::size_of;
+use core::net::IpAddr;
+use core::ptr::null_mut;
+
+use ngx::ffi::{ngx_uint_t, time_t};
+
+use crate::ngx_ext::shm::{LockedSlabPool, SharedData, SharedZone, SlabPool};
+use crate::util::push_json_str;
+
+pub type BanZone = SharedZone<BanTable>;
+
+// strict_sni_ban
+#[derive(Debug)]
+pub struct BanConfig {
+    pub zone: BanZone,
+    // violations within the window to be banned
+    pub threshold: u32,
+    // in seconds
+    pub window: time_t,
+    pub duration: time_t,
+    pub status: ngx_uint_t,
+}
+
+const BUCKETS: usize = 1024;
+
+// lives in the shared memory zone, and is touched only under the lock of the zone.
+#[derive(Debug)]
+#[repr(C)]
+pub struct BanTable {
+    buckets: [Cell<*mut BanEntry>; BUCKETS],
+}
+
+#[derive(Debug)]
+#[repr(C)]
+struct BanEntry {
+    next: *mut BanEntry,
+    addr: IpAddr,
+    window_start: time_t,
+    violations: u32,
+    banned_until: time_t,
+}
+
+impl SharedData for BanTable {
+    fn create(pool: &LockedSlabPool) -> Option<*mut Self> {
+        pool.calloc(size_of::<BanTable>())
+    }
+}
+
+impl BanEntry {
+    fn is_banned(&self, now: time_t) -> bool {
+        self.banned_until > now
+    }
+    // neither banned nor counting violations any more
+    fn is_expired(&self, now: time_t, window: time_t) -> bool {
+        !self.is_banned(now) && now - self.window_start >= window
+    }
+}
+
+impl BanTable {
+    pub fn is_banned(
+        &self,
+        pool: &SlabPool,
+        config: &BanConfig,
+        addr: IpAddr,
+        now: time_t,
+    ) -> bool {
+        pool.lock(|locked| {
+            self.find(locked, config, addr, now)
+                .is_some_and(|entry| unsafe { &*entry }.is_banned(now))
+        })
+    }
+
+    // counts a violation of the client, and tells whether it is banned from now on.
+    // a full zone just does not ban new clients.
+    pub fn strike(&self, pool: &SlabPool, config: &BanConfig, addr: IpAddr, now: time_t) -> bool {
+        pool.lock(|locked| {
+            let entry = match self.find(locked, config, addr, now) {
+                Some(entry) => entry,
+                None => {
+                    let Some(entry) = locked.calloc::<BanEntry>(size_of::<BanEntry>()) else {
+                        return false;
+                    };
+                    let bucket = &self.buckets[bucket(addr)];
+                    unsafe {
+                        entry.write(BanEntry {
+                            next: bucket.get(),
+                            addr,
+                            window_start: now,
+                            violations: 0,
+                            banned_until: 0,
+                        })
+                    };
+                    bucket.set(entry);
+                    entry
+                }
+            };
+            let entry = unsafe { &mut *entry };
+            if entry.is_banned(now) {
+                return false;
+            }
+            if now - entry.window_start >= config.window {
+                entry.window_start = now;
+                entry.violations = 0;
+            }
+            entry.violations += 1;
+            if entry.violations < config.threshold {
+                return false;
+            }
+            entry.banned_until = now + config.duration;
+            entry.violations = 0;
+            true
+        })
+    }
+
+    // the entry of the address, dropping the expired ones in the same bucket on the way
+    fn find(
+        &self,
+        locked: &LockedSlabPool,
+        config: &BanConfig,
+        addr: IpAddr,
+        now: time_t,
+    ) -> Option<*mut BanEntry> {
+        let bucket = &self.buckets[bucket(addr)];
+        let mut found = None;
+        let mut prev: *mut BanEntry = null_mut();
+        let mut p = bucket.get();
+        while let Some(entry) = unsafe { p.as_mut() } {
+            let next = entry.next;
+            if entry.is_expired(now, config.window) {
+                match unsafe { prev.as_mut() } {
+                    Some(prev) => prev.next = next,
+                    None => bucket.set(next),
+                }
+                locked.free(p);
+            } else {
+                if entry.addr == addr {
+                    found = Some(p);
+                }
+                prev = p;
+            }
+            p = next;
+        }
+        found
+    }
+
+    // [{"addr":..,"expires_in":<seconds>},..] of the clients banned now
+    pub fn render_json(&self, pool: &SlabPool, now: time_t) -> String {
+        let mut out = String::from("[");
+        pool.lock(|_| {
+            let mut first = true;
+            for bucket in &self.buckets {
+                let mut p = bucket.get();
+                while let Some(entry) = unsafe { p.as_ref() } {
+                    if entry.is_banned(now) {
+                        if !first {
+                            out.push(',');
+                        }
+                        first = false;
+                        out.push_str("{\"addr\":");
+                        push_json_str(&mut out, &entry.addr.to_string());
+                        out.push_str(&format!(",\"expires_in\":{}}}", entry.banned_until - now));
+                    }
+                    p = entry.next;
+                }
+            }
+        });
+        out.push_str("]\n");
+        out
+    }
+}
+
+// FNV-1a of the address
+fn bucket(addr: IpAddr) -> usize {
+    let octets = match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    };
+    let hash = octets.iter().fold(0xcbf29ce484222325u64, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    hash as usize % BUCKETS
+}
@@ -1,17 +1,32 @@
 //#![cfg_attr(not(test), no_std)]
 
+mod ban;
 mod logic;
 
 #[allow(dead_code)]
 mod ngx_ext;
 
+mod pattern;
+mod stats;
 mod util;
 
 use core::ffi::CStr;
 use core::ptr::addr_of_mut;
+use std::sync::OnceLock;
 
-use logic::{Analysis, PostReadHandler, PreaccessHandler};
-use ngx::ffi::{ngx_conf_t, ngx_str_t};
+use ban::BanConfig;
+use logic::{
+    ban_list_handler, metrics_handler, status_handler, FirstAuthority, ForwardedVariable,
+    HeaderHostVariable, HeaderPortVariable, HostResultVariable, LineAuthorityVariable,
+    PortResultVariable, PostReadHandler, PreaccessHandler, ReasonVariable, RequestCtx,
+    ResultVariable, XForwardedHostVariable,
+};
+use ngx::ffi::{
+    ngx_conf_t, ngx_http_core_srv_conf_t, ngx_parse_size, ngx_parse_time, ngx_str_t, ngx_uint_t,
+    time_t, NGX_HTTP_BAD_REQUEST, NGX_HTTP_CLOSE, NGX_HTTP_FORBIDDEN, NGX_HTTP_MISDIRECTED_REQUEST,
+    NGX_HTTP_NOT_ALLOWED, NGX_HTTP_PERMANENT_REDIRECT, NGX_HTTP_TEMPORARY_REDIRECT, NGX_LOG_ALERT,
+    NGX_LOG_CRIT, NGX_LOG_EMERG, NGX_LOG_ERR, NGX_LOG_INFO, NGX_LOG_NOTICE, NGX_LOG_WARN,
+};
 use ngx::http::{
     ConfCreateError, ConfInitError, ConfigurationDelegate, DefaultMerge, HttpLocConf, HttpMainConf,
     InitConfSetting, Merge, MergeConfigError, NgxHttpModule, NgxHttpModuleCommands,
@@ -27,7 +42,14 @@ use ngx::{
     exhibit_modules,
     http::{HttpModule, HttpModuleSkel},
 };
-use ngx_ext::http::variable::{GetHook, VariableHook};
+use ngx_ext::conf::ConfExt;
+use ngx_ext::http::complex_value::{CompileComplexValue, ComplexValue};
+use ngx_ext::http::variable::{AddVariable, GetHook, VariableAddError, VariableHook};
+use ngx_ext::shm::AddSharedZone;
+use ngx_ext::ssl::SslExData;
+use pattern::{HostAlias, HostPattern};
+use stats::StatsZone;
+use util::Cidr;
 
 // module exporter
 // this macro uses variable name directly.
@@ -44,10 +66,28 @@ impl HttpModule for StrictSniHttpModule {
     const NAME: &'static CStr = c"strict_sni_module";
 
     const COMMANDS: NgxHttpModuleCommandsRefMut<Self> = {
-        static mut COMMANDS: NgxHttpModuleCommands<StrictSniHttpModule, 3> =
+        static mut COMMANDS: NgxHttpModuleCommands<StrictSniHttpModule, 21> =
             NgxModuleCommandsBuilder::new()
                 .add::<StrictSniCommand>()
                 .add::<DirectFilterCommand>()
+                .add::<AliasCommand>()
+                .add::<ExemptAddrCommand>()
+                .add::<RejectCommand>()
+                .add::<OnMismatchCommand>()
+                .add::<ModeCommand>()
+                .add::<LogLevelCommand>()
+                .add::<ProxyProtocolCommand>()
+                .add::<PortMapCommand>()
+                .add::<PublicPortCommand>()
+                .add::<SniSourceCommand>()
+                .add::<ForwardedCommand>()
+                .add::<ConnectCommand>()
+                .add::<CoalescingCommand>()
+                .add::<ZoneCommand>()
+                .add::<StatusCommand>()
+                .add::<MetricsCommand>()
+                .add::<BanCommand>()
+                .add::<BanListCommand>()
                 .build();
         unsafe { NgxHttpModuleCommandsRefMut::from_mut(&mut *addr_of_mut!(COMMANDS)) }
     };
@@ -60,14 +100,14 @@ impl HttpModule for StrictSniHttpModule {
 
     type ThreadDelegate = ();
 
-    type PreConfiguration = ();
+    type PreConfiguration = StrictSniPreConfig;
 
     type PostConfiguration = StrictSniPostConfig;
 
     type MainConfSetting = StrictSniMainConfManager;
     type SrvConfSetting = DefaultMerge<()>;
     type LocConfSetting = DefaultMerge<ValidationConfig>;
-    type Ctx = Analysis;
+    type Ctx = RequestCtx;
     // fn init_module(cycle: &mut ngx_cycle_t) -> ngx_int_t {
     //     ngx_log_debug!(cycle.log, "strict_sni module init_master called");
     //     if !cycle.modules.is_null() {
@@ -96,6 +136,26 @@ impl HttpModule for StrictSniHttpModule {
     // }
 }
 
+struct StrictSniPreConfig;
+impl ConfigurationDelegate for StrictSniPreConfig {
+    fn configuration(cf: &mut ngx_conf_t) -> Result<(), ngx::core::Status> {
+        add_variables(cf).map_err(|_| ngx::core::Status::NGX_ERROR)
+    }
+}
+
+fn add_variables(cf: &mut ngx_conf_t) -> Result<(), VariableAddError> {
+    cf.add_variable::<ResultVariable>()?;
+    cf.add_variable::<ReasonVariable>()?;
+    cf.add_variable::<PortResultVariable>()?;
+    cf.add_variable::<HostResultVariable>()?;
+    cf.add_variable::<HeaderHostVariable>()?;
+    cf.add_variable::<HeaderPortVariable>()?;
+    cf.add_variable::<LineAuthorityVariable>()?;
+    cf.add_variable::<XForwardedHostVariable>()?;
+    cf.add_variable::<ForwardedVariable>()?;
+    Ok(())
+}
+
 struct StrictSniPostConfig;
 impl ConfigurationDelegate for StrictSniPostConfig {
     fn configuration(cf: &mut ngx_conf_t) -> Result<(), ngx::core::Status> {
@@ -129,26 +189,51 @@ struct StrictSniCommon {
     host: VariableHook,
     scheme: VariableHook,
     sni: VariableHook,
+    // the first authority requested on each TLS connection
+    authorities: SslExData<FirstAuthority>,
+    // the TCP ports of each server block, as the origins of its HTTP/3 requests
+    server_tcp_ports: Vec<(*const ngx_http_core_srv_conf_t, u16)>,
 }
 
+// an index of OpenSSL is never freed, so it is kept across configuration loads
+static AUTHORITIES: OnceLock<SslExData<FirstAuthority>> = OnceLock::new();
+
+// the validator of http level for the post_read phase, and the shared zones
+type StrictSniMainConf = (
+    Option<StrictSniCommon>,
+    ValidationConfig,
+    Option<StatsZone>,
+    Option<BanConfig>,
+);
+
 struct StrictSniMainConfManager;
 impl InitConfSetting for StrictSniMainConfManager {
-    type Conf = (Option<StrictSniCommon>, ValidationConfig);
+    type Conf = StrictSniMainConf;
 
     fn create(_: &mut ngx_conf_t) -> Result<Self::Conf, ConfCreateError> {
         Ok(Default::default())
     }
 
-    fn init(cf: &mut ngx_conf_t, (common, _): &mut Self::Conf) -> Result<(), ConfInitError> {
+    fn init(cf: &mut ngx_conf_t, (common, _, _, _): &mut Self::Conf) -> Result<(), ConfInitError> {
         let vr_host = cf.hook(&ngx_string!("host")).map_err(|_| ConfInitError)?;
         let vr_scheme = cf.hook(&ngx_string!("scheme")).map_err(|_| ConfInitError)?;
         let vr_sni = cf
             .hook(&ngx_string!("ssl_server_name"))
             .map_err(|_| ConfInitError)?;
+        let authorities = match AUTHORITIES.get() {
+            Some(authorities) => *authorities,
+            None => {
+                let authorities = SslExData::new().map_err(|_| ConfInitError)?;
+                *AUTHORITIES.get_or_init(|| authorities)
+            }
+        };
         *common = Some(StrictSniCommon {
             host: vr_host,
             scheme: vr_scheme,
             sni: vr_sni,
+            authorities,
+            // the listen directives are there only while the http block is parsed
+            server_tcp_ports: cf.server_tcp_ports(),
         });
         Ok(())
     }
@@ -159,6 +244,24 @@ struct ValidationConfig {
     rfc_mode: CheckSwitch<()>,
     port_mode: CheckSwitch<()>,
     host_mode: CheckSwitch<HostCheckRigor>,
+    host_target: Option<HostCheckTarget>,
+    aliases: Vec<HostAlias>,
+    exempt: Vec<Cidr>,
+    port_reject: Option<RejectAction>,
+    host_reject: Option<RejectAction>,
+    on_mismatch: Option<MismatchAction>,
+    mode: Option<ValidationMode>,
+    log_level: Option<ngx_uint_t>,
+    // the SNI and the port are told by the PROXY protocol header
+    proxy_protocol: Option<bool>,
+    // (listening port, public port), for NAT and container port publishing
+    port_map: Vec<(u16, u16)>,
+    // of any listening port not in the map
+    public_port: Option<u16>,
+    sni_source: Option<SniSource>,
+    forwarded: Option<ForwardedCheck>,
+    connect: Option<ConnectPolicy>,
+    coalescing: Option<CoalescingPolicy>,
 }
 
 // impl Drop for ModuleConfig {
@@ -181,10 +284,152 @@ enum HostCheckRigor {
     Strict,
 }
 
-// #[derive(Debug, Clone)]
-// enum RfcChecRigor {
+// what the requested host is checked against
+#[derive(Debug, Default, Clone)]
+enum HostCheckTarget {
+    // the SNI name itself
+    #[default]
+    Sni,
+    // the SAN of the certificate served on the connection
+    Cert,
+    // the virtual server selected by SNI on the handshake
+    Server,
+}
 
-// }
+// strict_sni_forwarded: X-Forwarded-Host and Forwarded host= held to the rules of Host
+#[derive(Debug, Clone)]
+enum ForwardedCheck {
+    Off,
+    Reject,
+    // passed, but the $strict_sni_*forwarded* variables are empty
+    Strip,
+}
+
+// strict_sni_connect: what is done with a CONNECT request of an authority-form target
+#[derive(Debug, Clone)]
+enum ConnectPolicy {
+    // the target is checked like an absolute-form one
+    Validate,
+    // passes every check
+    Exempt,
+    Reject,
+}
+
+// strict_sni_coalescing: which authorities may follow the first one on a TLS connection
+#[derive(Debug, Clone)]
+enum CoalescingPolicy {
+    // the first one only
+    Off,
+    // any the certificate of the connection covers
+    SameCert,
+    // any routed to the server of the first one
+    SameServer,
+    Allow,
+}
+
+// the SNI told by a trusted peer, e.g. in a header set by an L7 TLS offloader
+#[derive(Debug, Clone)]
+struct SniSource {
+    value: ComplexValue,
+    trusted: Vec<Cidr>,
+}
+
+// how a failed request is answered
+#[derive(Debug, Clone, Copy)]
+struct RejectAction {
+    status: ngx_uint_t,
+    body: Option<ComplexValue>,
+}
+
+impl RejectAction {
+    const MISDIRECTED_REQUEST: RejectAction = RejectAction {
+        status: NGX_HTTP_MISDIRECTED_REQUEST as ngx_uint_t,
+        body: None,
+    };
+    const BAD_REQUEST: RejectAction = RejectAction {
+        status: NGX_HTTP_BAD_REQUEST as ngx_uint_t,
+        body: None,
+    };
+    const NOT_ALLOWED: RejectAction = RejectAction {
+        status: NGX_HTTP_NOT_ALLOWED as ngx_uint_t,
+        body: None,
+    };
+}
+
+// what is done with a request failing the port or host check
+#[derive(Debug, Default, Clone)]
+enum MismatchAction {
+    // answer with strict_sni_reject
+    #[default]
+    Reject,
+    // redirect to the same path on the SNI name and the connection port, with 307 or 308
+    Redirect(ngx_uint_t),
+}
+
+#[derive(Debug, Default, Clone)]
+enum ValidationMode {
+    #[default]
+    Enforce,
+    // log the failed requests, but let them through
+    Report,
+}
+
+impl ValidationConfig {
+    // shared by strict_sni and strict_sni_direct_filter
+    fn apply_switch(&mut self, arg: &str) -> Result<(), CommandError> {
+        // good old on/off
+        if arg.eq_ignore_ascii_case("on") {
+            self.port_mode = CheckSwitch::On(());
+            self.host_mode = CheckSwitch::On(HostCheckRigor::Normal);
+        } else if arg.eq_ignore_ascii_case("off") {
+            self.rfc_mode = CheckSwitch::Off;
+            self.port_mode = CheckSwitch::Off;
+            self.host_mode = CheckSwitch::Off;
+        } else if arg.eq_ignore_ascii_case("strict") {
+            self.port_mode = CheckSwitch::On(());
+            self.host_mode = CheckSwitch::On(HostCheckRigor::Strict);
+        }
+        // rfc only setting
+        else if arg.eq_ignore_ascii_case("rfc") {
+            self.rfc_mode = CheckSwitch::On(());
+        } else if arg.eq_ignore_ascii_case("no_rfc") {
+            self.rfc_mode = CheckSwitch::Off;
+        }
+        // port only setting
+        else if arg.eq_ignore_ascii_case("port") {
+            self.port_mode = CheckSwitch::On(());
+        } else if arg.eq_ignore_ascii_case("no_port") {
+            self.port_mode = CheckSwitch::Off;
+        }
+        // host only setting
+        else if arg.eq_ignore_ascii_case("host") {
+            self.host_mode = CheckSwitch::On(HostCheckRigor::Normal);
+        } else if arg.eq_ignore_ascii_case("strict_host") {
+            self.host_mode = CheckSwitch::On(HostCheckRigor::Strict);
+        } else if arg.eq_ignore_ascii_case("no_host") {
+            self.host_mode = CheckSwitch::Off;
+        }
+        // host target setting, which implies the host check
+        else if arg.eq_ignore_ascii_case("sni") {
+            self.host_target = Some(HostCheckTarget::Sni);
+            self.enable_host_mode();
+        } else if arg.eq_ignore_ascii_case("cert") {
+            self.host_target = Some(HostCheckTarget::Cert);
+            self.enable_host_mode();
+        } else if arg.eq_ignore_ascii_case("server") {
+            self.host_target = Some(HostCheckTarget::Server);
+            self.enable_host_mode();
+        } else {
+            return Err(CommandError);
+        }
+        Ok(())
+    }
+    fn enable_host_mode(&mut self) {
+        if !matches!(self.host_mode, CheckSwitch::On(_)) {
+            self.host_mode = CheckSwitch::On(HostCheckRigor::Normal);
+        }
+    }
+}
 
 impl Merge for ValidationConfig {
     fn merge(&mut self, prev: &ValidationConfig) -> Result<(), MergeConfigError> {
@@ -197,6 +442,52 @@ impl Merge for ValidationConfig {
         if let CheckSwitch::Unset = self.host_mode {
             self.host_mode = prev.host_mode.clone();
         };
+        if self.host_target.is_none() {
+            self.host_target = prev.host_target.clone();
+        };
+        // like add_header, the list is inherited only if none is given on this level
+        if self.aliases.is_empty() {
+            self.aliases = prev.aliases.clone();
+        };
+        if self.exempt.is_empty() {
+            self.exempt = prev.exempt.clone();
+        };
+        if self.port_reject.is_none() {
+            self.port_reject = prev.port_reject;
+        };
+        if self.host_reject.is_none() {
+            self.host_reject = prev.host_reject;
+        };
+        if self.on_mismatch.is_none() {
+            self.on_mismatch = prev.on_mismatch.clone();
+        };
+        if self.mode.is_none() {
+            self.mode = prev.mode.clone();
+        };
+        if self.log_level.is_none() {
+            self.log_level = prev.log_level;
+        };
+        if self.proxy_protocol.is_none() {
+            self.proxy_protocol = prev.proxy_protocol;
+        };
+        if self.port_map.is_empty() {
+            self.port_map = prev.port_map.clone();
+        };
+        if self.public_port.is_none() {
+            self.public_port = prev.public_port;
+        };
+        if self.sni_source.is_none() {
+            self.sni_source = prev.sni_source.clone();
+        };
+        if self.forwarded.is_none() {
+            self.forwarded = prev.forwarded.clone();
+        };
+        if self.connect.is_none() {
+            self.connect = prev.connect.clone();
+        };
+        if self.coalescing.is_none() {
+            self.coalescing = prev.coalescing.clone();
+        };
         Ok(())
     }
 }
@@ -212,87 +503,562 @@ impl Command for StrictSniCommand {
         CommandContextFlag::HttpLoc
     );
 
-    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::OneMore);
 
     fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
-        if let Some(args) = unsafe { cf.args.as_ref() } {
-            if let Some(ngx_arg) = unsafe { (args.elts as *mut ngx_str_t).add(1).as_ref() } {
-                let arg = ngx_arg.to_str();
-                // good old on/off
-                if arg.eq_ignore_ascii_case("on") {
-                    conf.port_mode = CheckSwitch::On(());
-                    conf.host_mode = CheckSwitch::On(HostCheckRigor::Normal);
-                } else if arg.eq_ignore_ascii_case("off") {
-                    conf.port_mode = CheckSwitch::Off;
-                    conf.host_mode = CheckSwitch::Off;
-                } else if arg.eq_ignore_ascii_case("strict") {
-                    conf.port_mode = CheckSwitch::On(());
-                    conf.host_mode = CheckSwitch::On(HostCheckRigor::Strict);
-                }
-                // port only setting
-                if arg.eq_ignore_ascii_case("port") {
-                    conf.port_mode = CheckSwitch::On(());
-                } else if arg.eq_ignore_ascii_case("no_port") {
-                    conf.port_mode = CheckSwitch::Off;
-                }
-                // host only setting
-                if arg.eq_ignore_ascii_case("host") {
-                    conf.host_mode = CheckSwitch::On(HostCheckRigor::Normal);
-                } else if arg.eq_ignore_ascii_case("strict_host") {
-                    conf.host_mode = CheckSwitch::On(HostCheckRigor::Strict);
-                } else if arg.eq_ignore_ascii_case("no_host") {
-                    conf.host_mode = CheckSwitch::Off;
-                }
-                return Ok(());
-            };
+        for ngx_arg in cf.args() {
+            conf.apply_switch(ngx_arg.to_str())?;
         }
-        Err(CommandError)
+        Ok(())
     }
 }
 
 struct DirectFilterCommand;
 impl Command for DirectFilterCommand {
-    type CallRule = HttpMainConf<(Option<StrictSniCommon>, ValidationConfig)>;
+    type CallRule = HttpMainConf<StrictSniMainConf>;
     const NAME: ngx_str_t = ngx_string!("strict_sni_direct_filter");
 
     const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(CommandContextFlag::HttpMain);
 
-    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::OneMore);
 
     fn handler(
         cf: &mut ngx_conf_t,
-        (_, conf): &mut (Option<StrictSniCommon>, ValidationConfig),
+        (_, conf, _, _): &mut StrictSniMainConf,
     ) -> Result<(), CommandError> {
-        if let Some(args) = unsafe { cf.args.as_ref() } {
-            if let Some(ngx_arg) = unsafe { (args.elts as *mut ngx_str_t).add(1).as_ref() } {
-                let arg = ngx_arg.to_str();
-                // good old on/off
-                if arg.eq_ignore_ascii_case("on") {
-                    conf.port_mode = CheckSwitch::On(());
-                    conf.host_mode = CheckSwitch::On(HostCheckRigor::Normal);
-                } else if arg.eq_ignore_ascii_case("off") {
-                    conf.port_mode = CheckSwitch::Off;
-                    conf.host_mode = CheckSwitch::Off;
-                } else if arg.eq_ignore_ascii_case("strict") {
-                    conf.port_mode = CheckSwitch::On(());
-                    conf.host_mode = CheckSwitch::On(HostCheckRigor::Strict);
-                }
-                // port only setting
-                if arg.eq_ignore_ascii_case("port") {
-                    conf.port_mode = CheckSwitch::On(());
-                } else if arg.eq_ignore_ascii_case("no_port") {
-                    conf.port_mode = CheckSwitch::Off;
-                }
-                // host only setting
-                if arg.eq_ignore_ascii_case("host") {
-                    conf.host_mode = CheckSwitch::On(HostCheckRigor::Normal);
-                } else if arg.eq_ignore_ascii_case("strict_host") {
-                    conf.host_mode = CheckSwitch::On(HostCheckRigor::Strict);
-                } else if arg.eq_ignore_ascii_case("no_host") {
-                    conf.host_mode = CheckSwitch::Off;
+        for ngx_arg in cf.args() {
+            conf.apply_switch(ngx_arg.to_str())?;
+        }
+        Ok(())
+    }
+}
+
+struct ProxyProtocolCommand;
+impl Command for ProxyProtocolCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_proxy_protocol");
+
+    const CONTEXT_FLAG: CommandContextFlagSet =
+        context_flags!(CommandContextFlag::HttpMain, CommandContextFlag::HttpSrv);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        if let [flag] = cf.args() {
+            conf.proxy_protocol = Some(match flag.to_str() {
+                "on" if cfg!(not(ngx_proxy_protocol_tlv)) => {
+                    cf.log_emerg("\"strict_sni_proxy_protocol on\" requires nginx 1.23.2 or later");
+                    return Err(CommandError);
                 }
-                return Ok(());
+                "on" => true,
+                "off" => false,
+                _ => return Err(CommandError),
+            });
+            return Ok(());
+        }
+        Err(CommandError)
+    }
+}
+
+struct PortMapCommand;
+impl Command for PortMapCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_port_map");
+
+    const CONTEXT_FLAG: CommandContextFlagSet =
+        context_flags!(CommandContextFlag::HttpMain, CommandContextFlag::HttpSrv);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::OneMore);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        for arg in cf.args() {
+            for pair in arg.to_str().split(',') {
+                let (listen, public) = pair.split_once('=').ok_or(CommandError)?;
+                conf.port_map
+                    .push((parse_port(listen)?, parse_port(public)?));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct PublicPortCommand;
+impl Command for PublicPortCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_public_port");
+
+    const CONTEXT_FLAG: CommandContextFlagSet =
+        context_flags!(CommandContextFlag::HttpMain, CommandContextFlag::HttpSrv);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        if let [port] = cf.args() {
+            conf.public_port = Some(parse_port(port.to_str())?);
+            return Ok(());
+        }
+        Err(CommandError)
+    }
+}
+
+// 1..65535, without a sign
+fn parse_port(s: &str) -> Result<u16, CommandError> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(CommandError);
+    }
+    match s.parse() {
+        Ok(0) | Err(_) => Err(CommandError),
+        Ok(port) => Ok(port),
+    }
+}
+
+struct SniSourceCommand;
+impl Command for SniSourceCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_sni_source");
+
+    const CONTEXT_FLAG: CommandContextFlagSet =
+        context_flags!(CommandContextFlag::HttpSrv, CommandContextFlag::HttpLoc);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::OneMore);
+
+    // value cidr ...: never without the peers trusted
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        let args = cf.args().to_vec();
+        let [value, trusted @ ..] = &args[..] else {
+            return Err(CommandError);
+        };
+        if trusted.is_empty() {
+            return Err(CommandError);
+        }
+        let trusted = trusted
+            .iter()
+            .map(|cidr| Cidr::parse(cidr.to_str()).map_err(|_| CommandError))
+            .collect::<Result<_, _>>()?;
+        let value = cf.compile(value).map_err(|_| CommandError)?;
+        conf.sni_source = Some(SniSource { value, trusted });
+        Ok(())
+    }
+}
+
+struct ForwardedCommand;
+impl Command for ForwardedCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_forwarded");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        if let [check] = cf.args() {
+            conf.forwarded = Some(match check.to_str() {
+                "off" => ForwardedCheck::Off,
+                "reject" => ForwardedCheck::Reject,
+                "strip" => ForwardedCheck::Strip,
+                _ => return Err(CommandError),
+            });
+            return Ok(());
+        }
+        Err(CommandError)
+    }
+}
+
+struct ConnectCommand;
+impl Command for ConnectCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_connect");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        if let [policy] = cf.args() {
+            conf.connect = Some(match policy.to_str() {
+                "validate" => ConnectPolicy::Validate,
+                "exempt" => ConnectPolicy::Exempt,
+                "reject" => ConnectPolicy::Reject,
+                _ => return Err(CommandError),
+            });
+            return Ok(());
+        }
+        Err(CommandError)
+    }
+}
+
+struct CoalescingCommand;
+impl Command for CoalescingCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_coalescing");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        if let [policy] = cf.args() {
+            conf.coalescing = Some(match policy.to_str() {
+                "off" => CoalescingPolicy::Off,
+                "same_cert" => CoalescingPolicy::SameCert,
+                "same_server" => CoalescingPolicy::SameServer,
+                "allow" => CoalescingPolicy::Allow,
+                _ => return Err(CommandError),
+            });
+            return Ok(());
+        }
+        Err(CommandError)
+    }
+}
+
+struct ZoneCommand;
+impl Command for ZoneCommand {
+    type CallRule = HttpMainConf<StrictSniMainConf>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_zone");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(CommandContextFlag::HttpMain);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take2);
+
+    fn handler(
+        cf: &mut ngx_conf_t,
+        (_, _, zone, _): &mut StrictSniMainConf,
+    ) -> Result<(), CommandError> {
+        if zone.is_some() {
+            return Err(CommandError);
+        }
+        if let [name, size] = cf.args() {
+            let (name, mut size) = (*name, *size);
+            let size = unsafe { ngx_parse_size(&mut size) };
+            if name.len == 0 || size <= 0 {
+                return Err(CommandError);
+            }
+            let tag = unsafe { HttpModuleSkel::<StrictSniHttpModule>::SELF.to_ref() }.inner();
+            *zone = Some(
+                cf.add_shared_zone(&name, size as usize, tag)
+                    .map_err(|_| CommandError)?,
+            );
+            return Ok(());
+        }
+        Err(CommandError)
+    }
+}
+
+struct BanCommand;
+impl Command for BanCommand {
+    type CallRule = HttpMainConf<StrictSniMainConf>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_ban");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(CommandContextFlag::HttpMain);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::OneMore);
+
+    // threshold=N window=time duration=time zone=name[:size] [status=code]
+    fn handler(
+        cf: &mut ngx_conf_t,
+        (_, _, _, ban): &mut StrictSniMainConf,
+    ) -> Result<(), CommandError> {
+        if ban.is_some() {
+            return Err(CommandError);
+        }
+        let (mut threshold, mut window, mut duration, mut zone) = (None, None, None, None);
+        let mut status = NGX_HTTP_FORBIDDEN as ngx_uint_t;
+        let args = cf.args().to_vec();
+        for arg in &args {
+            let (key, value) = arg.to_str().split_once('=').ok_or(CommandError)?;
+            match key {
+                "threshold" => threshold = Some(value.parse().map_err(|_| CommandError)?),
+                "window" => window = Some(parse_seconds(value)?),
+                "duration" => duration = Some(parse_seconds(value)?),
+                "zone" => zone = Some(value),
+                "status" => status = value.parse().map_err(|_| CommandError)?,
+                _ => return Err(CommandError),
+            }
+        }
+        let (Some(threshold), Some(window), Some(duration), Some(zone)) =
+            (threshold, window, duration, zone)
+        else {
+            return Err(CommandError);
+        };
+        if threshold == 0 || !(400..500).contains(&status) {
+            return Err(CommandError);
+        }
+        // a megabyte holds about ten thousand clients
+        let (name, size) = zone.split_once(':').unwrap_or((zone, "1m"));
+        let mut size = ngx_str_t {
+            len: size.len(),
+            data: size.as_ptr() as *mut u8,
+        };
+        let size = unsafe { ngx_parse_size(&mut size) };
+        if name.is_empty() || size <= 0 {
+            return Err(CommandError);
+        }
+        let name = ngx_str_t {
+            len: name.len(),
+            data: name.as_ptr() as *mut u8,
+        };
+        let tag = unsafe { HttpModuleSkel::<StrictSniHttpModule>::SELF.to_ref() }.inner();
+        *ban = Some(BanConfig {
+            zone: cf
+                .add_shared_zone(&name, size as usize, tag)
+                .map_err(|_| CommandError)?,
+            threshold,
+            window,
+            duration,
+            status,
+        });
+        Ok(())
+    }
+}
+
+// e.g. 30s, 10m, 1h
+fn parse_seconds(value: &str) -> Result<time_t, CommandError> {
+    let mut value = ngx_str_t {
+        len: value.len(),
+        data: value.as_ptr() as *mut u8,
+    };
+    let seconds = unsafe { ngx_parse_time(&mut value, 1) };
+    if seconds <= 0 {
+        return Err(CommandError);
+    }
+    Ok(seconds)
+}
+
+struct BanListCommand;
+impl Command for BanListCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_ban_list");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(CommandContextFlag::HttpLoc);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::NoArgs);
+
+    fn handler(cf: &mut ngx_conf_t, _: &mut ValidationConfig) -> Result<(), CommandError> {
+        let clcf = cf.core_loc_conf().ok_or(CommandError)?;
+        clcf.handler = Some(ban_list_handler);
+        Ok(())
+    }
+}
+
+struct StatusCommand;
+impl Command for StatusCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_status");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(CommandContextFlag::HttpLoc);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::NoArgs);
+
+    fn handler(cf: &mut ngx_conf_t, _: &mut ValidationConfig) -> Result<(), CommandError> {
+        let clcf = cf.core_loc_conf().ok_or(CommandError)?;
+        clcf.handler = Some(status_handler);
+        Ok(())
+    }
+}
+
+struct MetricsCommand;
+impl Command for MetricsCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_metrics");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(CommandContextFlag::HttpLoc);
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::NoArgs);
+
+    fn handler(cf: &mut ngx_conf_t, _: &mut ValidationConfig) -> Result<(), CommandError> {
+        let clcf = cf.core_loc_conf().ok_or(CommandError)?;
+        clcf.handler = Some(metrics_handler);
+        Ok(())
+    }
+}
+
+struct AliasCommand;
+impl Command for AliasCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_alias");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take2);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        if let [sni, host] = cf.args() {
+            let sni = HostPattern::parse(sni.to_str()).map_err(|_| CommandError)?;
+            let host = HostPattern::parse(host.to_str()).map_err(|_| CommandError)?;
+            conf.aliases.push(HostAlias { sni, host });
+            return Ok(());
+        }
+        Err(CommandError)
+    }
+}
+
+struct ExemptAddrCommand;
+impl Command for ExemptAddrCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_exempt_addr");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::OneMore);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        for arg in cf.args() {
+            let cidr = Cidr::parse(arg.to_str()).map_err(|_| CommandError)?;
+            conf.exempt.push(cidr);
+        }
+        Ok(())
+    }
+}
+
+struct RejectCommand;
+impl Command for RejectCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_reject");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take123);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        let args = cf.args().to_vec();
+        // optional leading port | host, otherwise both
+        let (port, host, args) = match args.first().map(|arg| arg.to_str()) {
+            Some("port") => (true, false, &args[1..]),
+            Some("host") => (false, true, &args[1..]),
+            _ => (true, true, &args[..]),
+        };
+        let (status, body) = match args {
+            [status] => (status, None),
+            [status, body] => (status, Some(body)),
+            _ => return Err(CommandError),
+        };
+        // any 4xx, including 444 which closes the connection without response
+        let status: ngx_uint_t = status.to_str().parse().map_err(|_| CommandError)?;
+        if !(400..500).contains(&status) {
+            return Err(CommandError);
+        }
+        let body = match body {
+            Some(_) if status == NGX_HTTP_CLOSE as ngx_uint_t => return Err(CommandError),
+            Some(body) => Some(cf.compile(body).map_err(|_| CommandError)?),
+            None => None,
+        };
+        let action = RejectAction { status, body };
+        if port {
+            conf.port_reject = Some(action);
+        }
+        if host {
+            conf.host_reject = Some(action);
+        }
+        Ok(())
+    }
+}
+
+struct OnMismatchCommand;
+impl Command for OnMismatchCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_on_mismatch");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take12);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        let args: Vec<&str> = cf.args().iter().map(|arg| arg.to_str()).collect();
+        conf.on_mismatch = Some(match args[..] {
+            ["reject"] => MismatchAction::Reject,
+            ["redirect"] | ["redirect", "307"] => {
+                MismatchAction::Redirect(NGX_HTTP_TEMPORARY_REDIRECT as ngx_uint_t)
+            }
+            ["redirect", "308"] => {
+                MismatchAction::Redirect(NGX_HTTP_PERMANENT_REDIRECT as ngx_uint_t)
+            }
+            _ => return Err(CommandError),
+        });
+        Ok(())
+    }
+}
+
+struct ModeCommand;
+impl Command for ModeCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_mode");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        if let [mode] = cf.args() {
+            conf.mode = Some(match mode.to_str() {
+                "enforce" => ValidationMode::Enforce,
+                "report" => ValidationMode::Report,
+                _ => return Err(CommandError),
+            });
+            return Ok(());
+        }
+        Err(CommandError)
+    }
+}
+
+struct LogLevelCommand;
+impl Command for LogLevelCommand {
+    type CallRule = HttpLocConf<ValidationConfig>;
+    const NAME: ngx_str_t = ngx_string!("strict_sni_log_level");
+
+    const CONTEXT_FLAG: CommandContextFlagSet = context_flags!(
+        CommandContextFlag::HttpMain,
+        CommandContextFlag::HttpSrv,
+        CommandContextFlag::HttpLoc
+    );
+
+    const ARG_FLAG: CommandArgFlagSet = arg_flags!(CommandArgFlag::Take1);
+
+    fn handler(cf: &mut ngx_conf_t, conf: &mut ValidationConfig) -> Result<(), CommandError> {
+        if let [level] = cf.args() {
+            let level = match level.to_str() {
+                "emerg" => NGX_LOG_EMERG,
+                "alert" => NGX_LOG_ALERT,
+                "crit" => NGX_LOG_CRIT,
+                "error" => NGX_LOG_ERR,
+                "warn" => NGX_LOG_WARN,
+                "notice" => NGX_LOG_NOTICE,
+                "info" => NGX_LOG_INFO,
+                _ => return Err(CommandError),
             };
+            conf.log_level = Some(level as ngx_uint_t);
+            return Ok(());
         }
         Err(CommandError)
     }
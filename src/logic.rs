@@ -1,5 +1,12 @@
+use std::borrow::Cow;
+
+use core::cell::Cell;
+use core::net::IpAddr;
+use core::ptr;
 use core::str::from_utf8;
 
+use bitflags::bitflags;
+
 // use ngx::ffi::{
 //     nginx_version, ngx_array_push, ngx_command_t, ngx_conf_s, ngx_conf_t, ngx_connection_t,
 //     ngx_cycle_t, ngx_http_core_module, ngx_http_get_flushed_variable, ngx_http_get_variable,
@@ -15,15 +22,37 @@ use core::str::from_utf8;
 // };
 
 use ngx::{
-    core::Status,
-    http::{HTTPStatus, HttpHandler, Phase, Request},
-    ngx_log_debug_http,
+    core::{NgxStr, Status},
+    ffi::{
+        ngx_http_core_srv_conf_t, ngx_http_request_t, ngx_int_t, ngx_str_t, ngx_uint_t,
+        NGX_HTTP_GET, NGX_HTTP_HEAD, NGX_HTTP_INTERNAL_SERVER_ERROR, NGX_HTTP_NOT_ALLOWED,
+        NGX_HTTP_OK, NGX_HTTP_VERSION_11, NGX_HTTP_VERSION_20, NGX_LOG_ERR, NGX_LOG_INFO,
+        NGX_LOG_WARN,
+    },
+    http::{HttpHandler, Phase, Request},
+    ngx_log_debug_http, ngx_string,
 };
 
 use crate::{
-    ngx_ext::http::{request::RequestExt, variable::VariableHook},
-    util::{parse_host_header, parse_request_line},
-    CheckSwitch, HostCheckRigor, StrictSniCommon, StrictSniHttpModule, ValidationConfig,
+    ban::BanConfig,
+    ngx_ext::{
+        http::{
+            complex_value::ComplexValue,
+            request::RequestExt,
+            variable::{VariableGetter, VariableHook},
+        },
+        ssl::SslExData,
+        str::try_to_ref,
+    },
+    pattern::HostAlias,
+    stats::{Outcome, Stats},
+    util::{
+        canonicalize_host, host_ip, is_valid_host_header, parse_authority_form,
+        parse_forwarded_hosts, parse_host_header, parse_request_line, Cidr, RateLimiter,
+    },
+    CheckSwitch, CoalescingPolicy, ConnectPolicy, ForwardedCheck, HostCheckRigor, HostCheckTarget,
+    MismatchAction, RejectAction, SniSource, StrictSniCommon, StrictSniHttpModule,
+    ValidationConfig, ValidationMode,
 };
 
 pub(crate) struct PostReadHandler;
@@ -32,36 +61,36 @@ impl HttpHandler for PostReadHandler {
 
     fn handle(request: &mut Request) -> Status {
         ngx_log_debug_http!(request, "strict_sni post_read_handler called");
-        if let Some((common, main)) = request.main_conf::<StrictSniHttpModule>() {
+        if let Some((_, main, _, ban)) = request.main_conf::<StrictSniHttpModule>() {
             ngx_log_debug_http!(request, "strict_sni main config: {:?}", main);
-            if let Some(common) = common {
-                ngx_log_debug_http!(request, "strict_sni common: {:?}", common);
-                let aner: Analyzer = common.into();
-                if let Ok(analysis) = aner.analyze(request) {
-                    ngx_log_debug_http!(request, "strict_sni analyze succ");
-                    let mut pool = request.pool();
-                    let po = pool.allocate(analysis);
-                    if let Some(analysis) = unsafe { po.as_ref() } {
-                        ngx_log_debug_http!(request, "strict_sni pool alloc succ");
-                        request.set_ctx::<StrictSniHttpModule>(analysis);
-                        let val: Validator = main.into();
-                        return match val.validate(request, analysis) {
-                            Ok(()) => Status::NGX_DECLINED,
-                            Err(err_status) => err_status.into(),
-                        };
-                    } else {
-                        ngx_log_debug_http!(request, "strict_sni pool alloc nullptr ERR");
+            if let Some(ctx) = new_ctx(request) {
+                // a banned client is refused before any analysis, by the address
+                // realip gives it, so all is left to preaccess
+                if ban.is_some() {
+                    return Status::NGX_DECLINED;
+                }
+                if let Some(analysis) = ctx.analysis(request) {
+                    // no location is found yet, so loc_conf is the one of the server
+                    let val = direct_filter(main, request.loc_conf::<StrictSniHttpModule>());
+                    // a CONNECT is left to the policy of its location
+                    if analysis.reasons.contains(Reasons::CONNECT_METHOD)
+                        || direct_filter_deferred(&val, ban.as_ref())
+                    {
+                        return Status::NGX_DECLINED;
                     }
-                } else {
-                    ngx_log_debug_http!(request, "strict_sni analyze fail ERR");
+                    return match val.validate(request, analysis) {
+                        Ok(()) => Status::NGX_DECLINED,
+                        Err(failure) => val.fail(request, analysis, failure),
+                    };
                 }
             } else {
-                ngx_log_debug_http!(request, "strict_sni common None ERR");
+                ngx_log_debug_http!(request, "strict_sni ctx alloc nullptr ERR");
             }
         } else {
             ngx_log_debug_http!(request, "strict_sni main config nullptr ERR");
         }
 
+        record(request, Outcome::Error);
         Status::NGX_ERROR
     }
 }
@@ -75,21 +104,66 @@ impl HttpHandler for PreaccessHandler {
 
         if let Some(config) = request.loc_conf::<StrictSniHttpModule>() {
             ngx_log_debug_http!(request, "strict_sni config: {:?}", config);
-            if let Some(analysis) = request.get_ctx::<StrictSniHttpModule>() {
-                ngx_log_debug_http!(request, "strict_sni analysis: {:?}", analysis);
+            if let Some(ctx) = request.get_ctx::<StrictSniHttpModule>() {
                 //request.set_ctx::<StrictSniHttpModule>(an);
+                let Some((_, main, _, ban)) = request.main_conf::<StrictSniHttpModule>() else {
+                    ngx_log_debug_http!(request, "strict_sni main config nullptr ERR");
+                    return Status::NGX_ERROR;
+                };
+                if let Some(ban) = ban {
+                    if is_banned(request, ban) {
+                        ngx_log_debug_http!(request, "strict_sni client banned");
+                        return Status(ban.status as ngx_int_t);
+                    }
+                }
+                let Some(analysis) = ctx.analysis(request) else {
+                    record(request, Outcome::Error);
+                    return Status::NGX_ERROR;
+                };
+                ngx_log_debug_http!(request, "strict_sni analysis: {:?}", analysis);
+                // the direct filter left by post_read
+                let val = direct_filter(main, request.server_loc_conf::<StrictSniHttpModule>());
+                if !analysis.reasons.contains(Reasons::CONNECT_METHOD)
+                    && direct_filter_deferred(&val, ban.as_ref())
+                {
+                    if let Err(failure) = val.validate(request, analysis) {
+                        let status = val.fail(request, analysis, failure);
+                        if status != Status::NGX_DECLINED {
+                            return status;
+                        }
+                    }
+                }
+
+                let Some(analysis) = reanalyzed(request, config, ctx, analysis) else {
+                    ngx_log_debug_http!(request, "strict_sni reanalyze fail ERR");
+                    return Status::NGX_ERROR;
+                };
 
                 let val: Validator = config.into();
-                return match val.validate(request, analysis) {
-                    Ok(()) => Status::NGX_DECLINED,
-                    Err(err_status) => err_status.into(),
+                let status = match val.validate(request, analysis) {
+                    Ok(()) => {
+                        count(request, analysis, Outcome::Passed);
+                        // nothing is compared with it under allow, so it is not kept
+                        if !matches!(val.coalescing, CoalescingPolicy::Allow) {
+                            remember_authority(request, analysis);
+                        }
+                        Status::NGX_DECLINED
+                    }
+                    Err(failure) => val.fail(request, analysis, failure),
                 };
+                if status == Status::NGX_DECLINED
+                    && val.strips_forwarded(analysis)
+                    && !request.remove_headers(&["X-Forwarded-Host", "Forwarded"])
+                {
+                    return Status::NGX_ERROR;
+                }
+                return status;
             } else {
                 if request.is_internal() {
                     // internal redirect not inherit ctx, but it is ok
                     return Status::NGX_DECLINED;
                 }
-                ngx_log_debug_http!(request, "strict_sni analysis nullptr ERR: {:?}", request);
+                ngx_log_debug_http!(request, "strict_sni ctx nullptr ERR: {:?}", request);
             }
         } else {
             ngx_log_debug_http!(request, "strict_sni config nullptr ERR");
@@ -98,6 +172,265 @@ impl HttpHandler for PreaccessHandler {
     }
 }
 
+// the context of the request, with the peer as it is before realip
+fn new_ctx(request: &Request) -> Option<&RequestCtx> {
+    let ctx = RequestCtx {
+        peer: RequestExt::connection(request).and_then(|c| c.remote_addr()),
+        analysis: Cell::new(ptr::null()),
+    };
+    let mut pool = request.pool();
+    let ctx = unsafe { pool.allocate(ctx).as_ref() }?;
+    request.set_ctx::<StrictSniHttpModule>(ctx);
+    Some(ctx)
+}
+
+// the validator of strict_sni_direct_filter, with the mode and the exemptions
+// of the server level: post_read runs before any location is found.
+fn direct_filter<'a>(
+    main: &'a ValidationConfig,
+    server: Option<&'a ValidationConfig>,
+) -> Validator<'a> {
+    let mut val: Validator = main.into();
+    if let Some(conf) = server {
+        if let Some(mode) = &conf.mode {
+            val.mode = mode;
+        }
+        val.log_level = val.log_level.or(conf.log_level);
+        if !conf.exempt.is_empty() {
+            val.exempt = &conf.exempt;
+        }
+    }
+    val
+}
+
+// the client address is known after realip, so a direct filter
+// with exemptions or a ban is left from post_read to preaccess.
+fn direct_filter_deferred(val: &Validator, ban: Option<&BanConfig>) -> bool {
+    ban.is_some() || !val.exempt.is_empty()
+}
+
+// the analysis for a location with an SNI source of its own, made again
+// (the first one is made with the source of the server).
+fn reanalyzed<'a>(
+    request: &'a Request,
+    config: &ValidationConfig,
+    ctx: &'a RequestCtx,
+    analysis: &'a Analysis,
+) -> Option<&'a Analysis> {
+    let source = config
+        .sni_source
+        .as_ref()
+        .map(|source| source.value.as_ptr());
+    if source == analysis.sni_source.map(|value| value.as_ptr()) {
+        return Some(analysis);
+    }
+    let (common, _, _, _) = request.main_conf::<StrictSniHttpModule>()?;
+    let mut aner: Analyzer = common.as_ref()?.into();
+    aner = aner.with_conf(config);
+    aner.peer = ctx.peer;
+    let mut reanalysis = aner.analyze(request).ok()?;
+    // judged by the connection as the first analysis found it
+    reanalysis.coalesced = analysis.coalesced;
+    reanalysis.first_authority = analysis.first_authority.clone();
+    reanalysis.counted.set(analysis.counted.get());
+    ctx.set_analysis(request, reanalysis)
+}
+
+// into the zone if any, once per request:
+// a request failed in post_read, but let through by report mode, comes to preaccess again.
+fn count(request: &Request, analysis: &Analysis, outcome: Outcome) {
+    if analysis.counted.replace(true) {
+        return;
+    }
+    if matches!(outcome, Outcome::Rejected(_)) {
+        strike(request);
+    }
+    record(request, outcome);
+}
+
+// the first request to pass on a TLS connection, so that a rejected one
+// never decides what the later ones are compared with
+fn remember_authority(request: &Request, analysis: &Analysis) {
+    let Some(authority) = &analysis.first_authority else {
+        return;
+    };
+    let common = request
+        .main_conf::<StrictSniHttpModule>()
+        .and_then(|(common, _, _, _)| common.as_ref());
+    if let (Some(common), Some(c)) = (common, RequestExt::connection(request)) {
+        common.authorities.set(c, authority.clone());
+    }
+}
+
+fn record(request: &Request, outcome: Outcome) {
+    let Some(zone) = request
+        .main_conf::<StrictSniHttpModule>()
+        .and_then(|(_, _, zone, _)| zone.as_ref())
+    else {
+        return;
+    };
+    if let (Some(stats), Some(pool)) = (zone.data(), zone.pool()) {
+        let server = request
+            .connection_core_srv_conf()
+            .map_or(&[][..], |srv| try_to_ref(srv.server_name).as_bytes());
+        let port = RequestExt::connection(request)
+            .and_then(|c| c.local_port())
+            .unwrap_or(0);
+        stats.count(&pool, server, port, &outcome);
+    }
+}
+
+fn is_banned(request: &Request, ban: &BanConfig) -> bool {
+    let addr = RequestExt::connection(request).and_then(|c| c.remote_addr());
+    match (addr, ban.zone.data(), ban.zone.pool()) {
+        (Some(addr), Some(table), Some(pool)) => {
+            table.is_banned(&pool, ban, addr, request.get_inner().start_sec)
+        }
+        _ => false,
+    }
+}
+
+// a rejection of the client toward the ban, if any
+fn strike(request: &Request) {
+    let Some(ban) = request
+        .main_conf::<StrictSniHttpModule>()
+        .and_then(|(_, _, _, ban)| ban.as_ref())
+    else {
+        return;
+    };
+    let addr = RequestExt::connection(request).and_then(|c| c.remote_addr());
+    if let (Some(addr), Some(table), Some(pool)) = (addr, ban.zone.data(), ban.zone.pool()) {
+        if table.strike(&pool, ban, addr, request.get_inner().start_sec) {
+            request.log_error(
+                NGX_LOG_WARN as ngx_uint_t,
+                &format!(
+                    "strict_sni banned {} for {}s after {} violations",
+                    addr, ban.duration, ban.threshold
+                ),
+            );
+        }
+    }
+}
+
+// content handler of strict_sni_status
+pub(crate) unsafe extern "C" fn status_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let request = unsafe { Request::from_ngx_http_request(r) };
+    send_stats(
+        request,
+        "strict_sni_status",
+        &ngx_string!("application/json"),
+        Stats::render_json,
+    )
+}
+
+// content handler of strict_sni_metrics
+pub(crate) unsafe extern "C" fn metrics_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let request = unsafe { Request::from_ngx_http_request(r) };
+    send_stats(
+        request,
+        "strict_sni_metrics",
+        &ngx_string!("text/plain; version=0.0.4"),
+        Stats::render_prometheus,
+    )
+}
+
+// content handler of strict_sni_ban_list
+pub(crate) unsafe extern "C" fn ban_list_handler(r: *mut ngx_http_request_t) -> ngx_int_t {
+    let request = unsafe { Request::from_ngx_http_request(r) };
+    if request.get_inner().method & (NGX_HTTP_GET | NGX_HTTP_HEAD) as ngx_uint_t == 0 {
+        return NGX_HTTP_NOT_ALLOWED as ngx_int_t;
+    }
+    let Some((table, pool)) = request
+        .main_conf::<StrictSniHttpModule>()
+        .and_then(|(_, _, _, ban)| ban.as_ref())
+        .and_then(|ban| ban.zone.data().zip(ban.zone.pool()))
+    else {
+        request.log_error(
+            NGX_LOG_ERR as ngx_uint_t,
+            "strict_sni_ban_list requires strict_sni_ban",
+        );
+        return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+    };
+    let list = table.render_json(&pool, request.get_inner().start_sec);
+    send_text(request, &ngx_string!("application/json"), &list)
+}
+
+fn send_stats(
+    request: &Request,
+    directive: &str,
+    content_type: &ngx_str_t,
+    render: fn(&Stats) -> String,
+) -> ngx_int_t {
+    if request.get_inner().method & (NGX_HTTP_GET | NGX_HTTP_HEAD) as ngx_uint_t == 0 {
+        return NGX_HTTP_NOT_ALLOWED as ngx_int_t;
+    }
+    let Some(stats) = request
+        .main_conf::<StrictSniHttpModule>()
+        .and_then(|(_, _, zone, _)| zone.as_ref())
+        .and_then(|zone| zone.data())
+    else {
+        request.log_error(
+            NGX_LOG_ERR as ngx_uint_t,
+            &format!("{} requires strict_sni_zone", directive),
+        );
+        return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+    };
+    send_text(request, content_type, &render(stats))
+}
+
+fn send_text(request: &Request, content_type: &ngx_str_t, text: &str) -> ngx_int_t {
+    let Some(body) = ComplexValue::constant(request, text.as_bytes()) else {
+        return NGX_HTTP_INTERNAL_SERVER_ERROR as ngx_int_t;
+    };
+    request.send_response(NGX_HTTP_OK as ngx_uint_t, Some(content_type), &body)
+}
+
+// answer a failed request.
+// a status alone is left to the phase checker, which finalizes the request with it.
+// a body or a redirect is sent here like the return directive does,
+// so the phase must not finalize again.
+fn reject(request: &Request, analysis: &Analysis, rejection: Rejection) -> Status {
+    let (status, body) = match rejection {
+        Rejection::Reject(action) => (action.status, action.body),
+        Rejection::Redirect(status, fallback) => {
+            match redirect_location(request, analysis)
+                .and_then(|location| ComplexValue::constant(request, location.as_bytes()))
+            {
+                Some(location) => (status, Some(location)),
+                None => (fallback.status, fallback.body),
+            }
+        }
+    };
+    match body {
+        Some(body) => {
+            let rc = request.send_response(status, None, &body);
+            request.finalize(rc);
+            Status::NGX_DONE
+        }
+        None => Status(status as ngx_int_t),
+    }
+}
+
+// https://<sni>[:port]<request uri>, where the Host header is never used:
+// the SNI is canonical (see canonicalize_host) and is taken only if it is a plain DNS name.
+fn redirect_location(request: &Request, analysis: &Analysis) -> Option<String> {
+    let sni = analysis.sni.as_deref().filter(|sni| {
+        sni.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+    })?;
+    let uri = request
+        .unparsed_uri()
+        .and_then(|uri| uri.to_str().ok())
+        .filter(|uri| uri.starts_with('/'))
+        .unwrap_or("/");
+    let location = match analysis.conn_port {
+        None | Some(443) => format!("https://{}{}", sni, uri),
+        Some(port) => format!("https://{}:{}{}", sni, port, uri),
+    };
+    ngx_log_debug_http!(request, "strict_sni redirect to: {}", location);
+    Some(location)
+}
+
 // memo:
 // nginx won't confuse listening ip and port
 // but nginx won't check request (! not host) (host name / port) vs sni / listening port
@@ -137,6 +470,18 @@ struct Analyzer<'a> {
     host: &'a VariableHook,
     scheme: &'a VariableHook,
     sni: &'a VariableHook,
+    authorities: &'a SslExData<FirstAuthority>,
+    server_tcp_ports: &'a [(*const ngx_http_core_srv_conf_t, u16)],
+    // behind an L4 TLS terminator, which tells the handshake by the PROXY protocol header
+    proxy_protocol: bool,
+    // behind NAT or a container port publishing
+    port_map: &'a [(u16, u16)],
+    public_port: Option<u16>,
+    // behind an L7 TLS offloader, which tells the SNI by a header
+    sni_source: Option<&'a SniSource>,
+    // the peer of the connection, by default as it is on the analysis
+    // (after realip from preaccess on)
+    peer: Option<IpAddr>,
 }
 
 impl<'a> From<&'a StrictSniCommon> for Analyzer<'a> {
@@ -145,10 +490,37 @@ impl<'a> From<&'a StrictSniCommon> for Analyzer<'a> {
             host: &common.host,
             scheme: &common.scheme,
             sni: &common.sni,
+            authorities: &common.authorities,
+            server_tcp_ports: &common.server_tcp_ports,
+            proxy_protocol: false,
+            port_map: &[],
+            public_port: None,
+            sni_source: None,
+            peer: None,
         }
     }
 }
 impl<'a> Analyzer<'a> {
+    // with the settings of the server or the location.
+    // the analysis is made once per request with the settings of the server (see RequestCtx),
+    // so the directives read here are taken on the http and server levels only,
+    // but for the SNI source (see reanalyzed).
+    fn with_conf(mut self, conf: &'a ValidationConfig) -> Self {
+        self.proxy_protocol = conf.proxy_protocol.unwrap_or(false);
+        self.port_map = &conf.port_map;
+        self.public_port = conf.public_port;
+        self.sni_source = conf.sni_source.as_ref();
+        self
+    }
+    // the port the clients connect to for the listening one
+    fn public_port(&self, port: u16) -> u16 {
+        self.port_map
+            .iter()
+            .find(|(listen, _)| *listen == port)
+            .map(|(_, public)| *public)
+            .or(self.public_port)
+            .unwrap_or(port)
+    }
     fn get_var_host_str(&self, request: &'a Request) -> Option<&'a str> {
         if let Some(host_slice) = self.host.get(request) {
             return from_utf8(host_slice).ok();
@@ -203,22 +575,163 @@ impl<'a> Analyzer<'a> {
             None
         };
 
-        let line_hp = if let Some(rls) = request.request_line().and_then(|s| s.to_str().ok()) {
-            let hp = extract_line_host_port(rls);
-            ngx_log_debug_http!(
-                request,
-                "strict_sni request line parse succ: \"{}\" -> {:?}",
-                rls,
-                hp
-            );
+        let (connect, target_hp) =
+            if let Some(rls) = request.request_line().and_then(|s| s.to_str().ok()) {
+                let (connect, hp) = extract_line_host_port(rls);
+                ngx_log_debug_http!(
+                    request,
+                    "strict_sni request line parse succ: \"{}\" -> connect:{} {:?}",
+                    rls,
+                    connect,
+                    hp
+                );
+                (connect, hp)
+            } else {
+                (false, None)
+            };
+        // HTTP/2 and HTTP/3 name the target by :authority, not by the request line;
+        // it takes the place of the line authority in the port and rfc checks,
+        // and Some(None) is one that does not parse.
+        let authority_hp = request.authority().map(|authority| {
+            let hp = authority.to_str().ok().and_then(extract_header_host_port);
+            ngx_log_debug_http!(request, "strict_sni authority parse: {:?}", hp);
             hp
+        });
+        let line_hp = authority_hp.unwrap_or(target_hp);
+
+        let mut reasons = Reasons::empty();
+
+        let connection = RequestExt::connection(request);
+        let peer = self
+            .peer
+            .or_else(|| connection.and_then(|c| c.remote_addr()));
+        // the source is taken only from the trusted peers, otherwise it is the SNI as usual
+        let sni_source = self.sni_source.filter(|source| {
+            peer.is_some_and(|peer| source.trusted.iter().any(|cidr| cidr.contains(peer)))
+        });
+        let proxy = connection
+            .filter(|_| self.proxy_protocol)
+            .and_then(|c| c.proxy_protocol());
+        // PP2_TYPE_AUTHORITY is the SNI the client sent to the terminator;
+        // without it, the host check fails closed instead of passing for no SNI.
+        let raw_sni = if self.proxy_protocol {
+            let authority = connection
+                .and_then(|c| c.proxy_protocol_tlv("authority"))
+                .and_then(|tlv| from_utf8(tlv).ok())
+                .filter(|s| !s.is_empty());
+            if authority.is_none() {
+                reasons |= Reasons::PROXY_SNI_MISSING;
+            }
+            authority
+        } else if let Some(source) = sni_source {
+            source
+                .value
+                .get(request)
+                .and_then(|value| from_utf8(value).ok())
+                .filter(|s| !s.is_empty())
         } else {
-            None
+            // ssl_server_name is empty (not "not found") when the client sent no SNI.
+            self.get_var_sni_str(request).filter(|s| !s.is_empty())
+        };
+
+        // a Host header that is there but does not parse fails the checks, instead of skipping them
+        if request.host_header().is_some() && header_hp.is_none() {
+            reasons |= Reasons::HOST_UNPARSABLE;
+        }
+        if connect {
+            reasons |= Reasons::CONNECT_METHOD;
+        }
+        if (connect || authority_hp.is_some()) && line_hp.is_none() {
+            reasons |= Reasons::LINE_UNPARSABLE;
+        }
+
+        // every host source is compared in one canonical form (see canonicalize_host);
+        // a name without one is invalid and fails the host check as a whole.
+        let mut canonicalize = |name: &str| match canonicalize_host(name) {
+            Ok(canonical) => Some(canonical),
+            Err(_) => {
+                reasons |= Reasons::NAME_INVALID;
+                None
+            }
         };
+        let header_host = header_hp.and_then(|hp| canonicalize(hp.0));
+        let line_host = line_hp.and_then(|lp| canonicalize(lp.0));
+        // but for the host check, :authority is no request target: nginx routes by it
+        // as by Host, so it is judged by the host target as $host is (see below).
+        let target_host = line_host.clone().filter(|_| authority_hp.is_none());
+        let sni = raw_sni.and_then(&mut canonicalize);
+        let select_host = self.get_var_host_str(request).and_then(&mut canonicalize);
+        ngx_log_debug_http!(
+            request,
+            "strict_sni canonical: header:{:?} line:{:?} sni:{:?} select_host:{:?}",
+            header_host,
+            line_host,
+            sni,
+            select_host
+        );
+
+        // RFC 9110 7.2 / RFC 9112 3.2 conformance:
+        // HTTP/1.1 must send Host, Host must be a bare authority,
+        // and must be identical to the absolute-form request target.
+        match request.host_header() {
+            Some(hh) => {
+                if !hh.to_str().is_ok_and(is_valid_host_header) {
+                    reasons |= Reasons::HOST_MALFORMED;
+                }
+            }
+            None => {
+                if request.http_version() == NGX_HTTP_VERSION_11 as ngx_uint_t {
+                    reasons |= Reasons::HOST_MISSING;
+                }
+            }
+        }
+        // Host beside :authority must not differ from it either (RFC 9113 8.3.1),
+        // and nginx may route by either of them.
+        if let (Some(hp), Some(lp)) = (header_hp, line_hp) {
+            if header_host.is_none() || header_host != line_host || hp.1 != lp.1 {
+                reasons |= match authority_hp {
+                    Some(_) => Reasons::AUTHORITY_HOST_MISMATCH,
+                    None => Reasons::HOST_LINE_MISMATCH,
+                };
+            }
+        }
+        // nginx keeps only the first Host field (into which HTTP/2 puts :authority before 1.23),
+        // so the rest are checked here: HTTP/1.x allows one (RFC 9112 3.2),
+        // and on HTTP/2 and HTTP/3 they must all name the same authority.
+        let host_values = request.header_values("Host");
+        if host_values.len() > 1 {
+            if request.http_version() < NGX_HTTP_VERSION_20 as ngx_uint_t {
+                reasons |= Reasons::HOST_MALFORMED;
+            } else {
+                let authority = |value: &NgxStr| {
+                    let (host, port) = value.to_str().ok().and_then(extract_header_host_port)?;
+                    Some((canonicalize_host(host).ok()?, port))
+                };
+                let first = authority(host_values[0]);
+                if first.is_none() || host_values[1..].iter().any(|&v| authority(v) != first) {
+                    reasons |= Reasons::AUTHORITY_HOST_MISMATCH;
+                }
+            }
+        }
 
         //ngx_log_debug_http!(request, "strict_sni port check activated");
-        let conn_port = RequestExt::connection(request).and_then(|c| c.local_port());
+        let conn_port = match proxy {
+            Some(proxy) => proxy.dst_port(),
+            None => connection
+                .and_then(|c| c.local_port())
+                .map(|port| self.public_port(port)),
+        };
+        // TLS on this connection, or on the client side of the terminator (PP2_CLIENT_SSL)
+        let is_tls = connection.is_some_and(|c| c.is_ssl());
+        let client_tls = match proxy {
+            Some(_) => connection
+                .and_then(|c| c.proxy_protocol_tlv("ssl"))
+                .and_then(|ssl| ssl.first())
+                .is_some_and(|client| client & PP2_CLIENT_SSL != 0),
+            None => is_tls,
+        };
         let scheme_port: Option<u16> = match self.get_var_scheme_str(request) {
+            _ if proxy.is_some() && client_tls => Some(443),
             Some(str) => match str {
                 "http" => Some(80),
                 "https" => Some(443),
@@ -226,66 +739,362 @@ impl<'a> Analyzer<'a> {
             },
             None => None,
         };
+        // on HTTP/3 the request names the origin, of which the TCP port advertises
+        // the UDP one by Alt-Svc, so the TCP ports of the server block of the QUIC listener
+        // (as found by SNI) are taken as well, but never those of another server.
+        let origin_ports = match connection.filter(|c| proxy.is_none() && c.is_quic()) {
+            Some(_) => server_ports(
+                self.server_tcp_ports,
+                request
+                    .connection_core_srv_conf()
+                    .map_or(ptr::null(), |srv| srv as *const _),
+            )
+            .into_iter()
+            .map(|port| self.public_port(port))
+            .collect(),
+            None => Vec::new(),
+        };
         ngx_log_debug_http!(
             request,
-            "strict_sni port: conn:{:?} scheme:{:?}",
+            "strict_sni port: conn:{:?} scheme:{:?} origin:{:?}",
             conn_port,
-            scheme_port
+            scheme_port,
+            origin_ports
         );
+        let port_valid = |req_port: Option<u16>| {
+            validate_port(conn_port, req_port, scheme_port)
+                || req_port
+                    .or(scheme_port)
+                    .is_some_and(|port| origin_ports.contains(&port))
+        };
 
-        let mut port_succ_flag: bool = true;
         if let Some(hp) = header_hp {
             let header_port = hp.1;
             ngx_log_debug_http!(request, "strict_sni port: header:{:?}", header_port);
-            port_succ_flag &= validate_port(conn_port, header_port, scheme_port);
+            if !port_valid(header_port) {
+                reasons |= Reasons::HEADER_PORT_MISMATCH;
+            }
         }
 
         if let Some(hp) = line_hp {
             let line_port = hp.1;
             ngx_log_debug_http!(request, "strict_sni port: line:{:?}", line_port);
-            port_succ_flag &= validate_port(conn_port, line_port, scheme_port);
+            if !port_valid(line_port) {
+                reasons |= Reasons::LINE_PORT_MISMATCH;
+            }
+        }
+
+        // X-Forwarded-Host and Forwarded host= are held to the rules of Host:
+        // the SNI (or Host, without SNI) and the port of the connection.
+        let expected_host = sni.as_ref().or(header_host.as_ref());
+        let forwarded_consistent = |host: &str| {
+            let Ok((name, port)) = parse_host_header(host) else {
+                return false;
+            };
+            let Ok(name) = canonicalize_host(name) else {
+                return false;
+            };
+            (expected_host.is_none() || expected_host == Some(&name)) && port_valid(port)
+        };
+        let forwarded = forwarded_hosts(request);
+        ngx_log_debug_http!(request, "strict_sni forwarded hosts: {:?}", forwarded);
+        if !forwarded
+            .iter()
+            .all(|host| host.as_deref().is_some_and(forwarded_consistent))
+        {
+            reasons |= Reasons::FORWARDED_MISMATCH;
         }
 
-        let mut host_succ_flag: bool = true;
-        if let Some(select_host) = self.get_var_host_str(request) {
-            ngx_log_debug_http!(request, "strict_sni select_host: {}", select_host);
-            if let Some(sni) = self.get_var_sni_str(request) {
-                ngx_log_debug_http!(request, "strict_sni sni: {}", sni);
-                if !eq_host_name(sni, select_host) {
-                    host_succ_flag = false;
+        // strict rigor additionally requires the TLS session to carry SNI at all,
+        // and the request to name its host by itself (not by server_name fallback).
+        let host_given = request.host_header().is_some() || line_hp.is_some();
+        ngx_log_debug_http!(
+            request,
+            "strict_sni tls: {} client tls: {} host given: {}",
+            is_tls,
+            client_tls,
+            host_given
+        );
+
+        // RFC 6066 forbids IP addresses in SNI, so an IP host has nothing to be compared with;
+        // on TLS it is accepted only for the very address the connection is made to,
+        // whatever the target of the host check is.
+        let local_ip = || match proxy {
+            Some(proxy) => proxy.dst_addr(),
+            None => connection.and_then(|c| c.local_addr()),
+        };
+        let ip_host = select_host
+            .as_deref()
+            .and_then(host_ip)
+            .filter(|_| client_tls);
+        if let Some(ip) = ip_host {
+            let local_ip = local_ip();
+            ngx_log_debug_http!(request, "strict_sni ip host: {} local: {:?}", ip, local_ip);
+            if local_ip != Some(ip.to_canonical()) {
+                reasons |= Reasons::IP_HOST_MISMATCH;
+            }
+        } else if let Some(select_host) = &select_host {
+            if let Some(sni) = &sni {
+                if sni != select_host {
+                    reasons |= Reasons::SNI_HOST_MISMATCH;
                 }
-                // match rigor {
-                //     HostCheckRigor::Strict => {}
-                //     HostCheckRigor::Normal => {
-                //         //unsafe { ngx_http_get_variable(request, name, key) };
-                //         let conn_info = get_scheme_info(conn);
-                //         match conn_info {
-                //             SchemeInfo::Http => {}
-                //             SchemeInfo::Https { sni } => todo!(),
-                //         };
-                //         //validate_all_host_headers(request, |host, port| {}, |_| a);
-                //         Ok(())
-                //     }
-                //     ValidationMode::HostOnly => {
-                //         // let (conn_host, _) = get_host_and_scheme(conn);
-                //         // let conn_host = conn_host.map(|s| s.to_str());
-                //         Ok(())
-                //     }
-                // }
             }
+            // the certificate is there even without SNI (the default one),
+            // and any name it covers is what the client would accept on this connection.
+            if let Some(covers) = connection.and_then(|c| c.certificate_covers(select_host)) {
+                ngx_log_debug_http!(request, "strict_sni cert covers: {}", covers);
+                if !covers {
+                    reasons |= Reasons::CERT_HOST_MISMATCH;
+                }
+            }
+        }
+        // nginx routes an absolute-form request by its target whatever Host says,
+        // so the target is compared with the SNI by itself, for any target and rigor.
+        if let (Some(target_host), Some(sni)) = (&target_host, &sni) {
+            let local =
+                host_ip(target_host).is_some_and(|ip| local_ip() == Some(ip.to_canonical()));
+            ngx_log_debug_http!(
+                request,
+                "strict_sni target host: {} local: {}",
+                target_host,
+                local
+            );
+            if target_host != sni && !local {
+                reasons |= Reasons::LINE_HOST_MISMATCH;
+            }
+        }
+        // nginx routed the request by Host; it should stay in the server chosen by SNI.
+        // (plain http has no handshake, so its connection server is just the default one)
+        if is_tls && ip_host.is_none() {
+            if let (Some(req_srv), Some(conn_srv)) =
+                (request.core_srv_conf(), request.connection_core_srv_conf())
+            {
+                if !ptr::eq(req_srv, conn_srv) {
+                    reasons |= Reasons::SERVER_HOST_MISMATCH;
+                }
+            }
+        }
+
+        // the first request to pass on a TLS connection is remembered (see remember_authority),
+        // and the later ones (HTTP/2 streams above all) are compared with it.
+        let mut first_authority = None;
+        let coalesced = match (connection.filter(|c| c.is_ssl()), &select_host) {
+            (Some(c), Some(host)) => {
+                let authority = FirstAuthority {
+                    host: host.clone(),
+                    port: line_hp.or(header_hp).and_then(|hp| hp.1),
+                    server: request
+                        .core_srv_conf()
+                        .map_or(ptr::null(), |srv| srv as *const _),
+                };
+                match self.authorities.get(c) {
+                    Some(first) => Some(Coalesced {
+                        same_authority: first.host == authority.host
+                            && first.port == authority.port,
+                        same_server: ptr::eq(first.server, authority.server),
+                    }),
+                    None => {
+                        first_authority = Some(authority);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        ngx_log_debug_http!(request, "strict_sni coalesced: {:?}", coalesced);
+
+        if client_tls && sni.is_none() {
+            reasons |= Reasons::SNI_MISSING;
+        }
+        if client_tls && !host_given {
+            reasons |= Reasons::HOST_NOT_GIVEN;
         }
+        ngx_log_debug_http!(request, "strict_sni reasons: {:?}", reasons);
 
         Ok(Analysis {
-            port_succ_flag,
-            host_succ_flag,
+            sni,
+            host: select_host,
+            line_host: target_host,
+            header_host: header_hp.map(|hp| hp.0.to_string()),
+            header_port: header_hp.and_then(|hp| hp.1),
+            line_authority: line_hp.map(|lp| match lp.1 {
+                Some(port) => format!("{}:{}", lp.0, port),
+                None => lp.0.to_string(),
+            }),
+            conn_port,
+            reasons,
+            sni_source: sni_source.map(|source| source.value),
+            coalesced,
+            first_authority,
+            counted: Cell::new(false),
         })
     }
 }
 
+// the module context of a request
+#[derive(Debug)]
+pub struct RequestCtx {
+    // the peer of the connection as post_read finds it, before realip
+    peer: Option<IpAddr>,
+    // in the request pool, made on the first need
+    analysis: Cell<*const Analysis>,
+}
+
+impl RequestCtx {
+    // made in post_read, or in preaccess once the client is found not banned,
+    // unless a variable of the module is read before
+    fn analysis(&self, request: &Request) -> Option<&Analysis> {
+        if let Some(analysis) = unsafe { self.analysis.get().as_ref() } {
+            return Some(analysis);
+        }
+        let Some(common) = request
+            .main_conf::<StrictSniHttpModule>()
+            .and_then(|(common, _, _, _)| common.as_ref())
+        else {
+            ngx_log_debug_http!(request, "strict_sni common None ERR");
+            return None;
+        };
+        let mut aner: Analyzer = common.into();
+        if let Some(conf) = request.server_loc_conf::<StrictSniHttpModule>() {
+            aner = aner.with_conf(conf);
+        }
+        aner.peer = self.peer;
+        let Ok(analysis) = aner.analyze(request) else {
+            ngx_log_debug_http!(request, "strict_sni analyze fail ERR");
+            return None;
+        };
+        self.set_analysis(request, analysis)
+    }
+    fn set_analysis(&self, request: &Request, analysis: Analysis) -> Option<&Analysis> {
+        let mut pool = request.pool();
+        let Some(analysis) = (unsafe { pool.allocate(analysis).as_ref() }) else {
+            ngx_log_debug_http!(request, "strict_sni pool alloc nullptr ERR");
+            return None;
+        };
+        self.analysis.set(analysis);
+        Some(analysis)
+    }
+}
+
 #[derive(Debug)]
 pub struct Analysis {
-    port_succ_flag: bool,
-    host_succ_flag: bool,
+    sni: Option<String>,
+    host: Option<String>,
+    // of an absolute-form request target, or of the authority-form one of CONNECT
+    line_host: Option<String>,
+    header_host: Option<String>,
+    header_port: Option<u16>,
+    line_authority: Option<String>,
+    conn_port: Option<u16>,
+    reasons: Reasons,
+    // the SNI source taken, if any
+    sni_source: Option<ComplexValue>,
+    // None for the first request on the connection
+    coalesced: Option<Coalesced>,
+    // to be remembered for the connection once the request passes, if none is yet
+    first_authority: Option<FirstAuthority>,
+    // into the statistics
+    counted: Cell<bool>,
+}
+
+// the first request on a TLS connection
+#[derive(Debug, Clone)]
+pub struct FirstAuthority {
+    host: String,
+    port: Option<u16>,
+    // the server it is routed to
+    server: *const ngx_http_core_srv_conf_t,
+}
+
+// a later request on the connection, as compared with the first one
+#[derive(Debug, Clone, Copy)]
+struct Coalesced {
+    same_authority: bool,
+    same_server: bool,
+}
+
+bitflags! {
+    // what is wrong with the request, grouped by the check it fails.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Reasons: u32 {
+        // rfc
+        // HTTP/1.1 without Host
+        const HOST_MISSING = 1 << 0;
+        // Host is not a bare ASCII authority
+        const HOST_MALFORMED = 1 << 1;
+        // Host differs from the absolute-form request target
+        const HOST_LINE_MISMATCH = 1 << 2;
+
+        // port and host
+        // Host does not parse as an authority at all
+        const HOST_UNPARSABLE = 1 << 3;
+        // the target of CONNECT is not in authority-form, or :authority does not parse
+        const LINE_UNPARSABLE = 1 << 4;
+
+        // port
+        const HEADER_PORT_MISMATCH = 1 << 5;
+        const LINE_PORT_MISMATCH = 1 << 6;
+
+        // host
+        // a host name without a canonical form
+        const NAME_INVALID = 1 << 7;
+        const SNI_HOST_MISMATCH = 1 << 8;
+        const CERT_HOST_MISMATCH = 1 << 9;
+        const SERVER_HOST_MISMATCH = 1 << 10;
+        // an IP host other than the local address on TLS, for any target
+        const IP_HOST_MISMATCH = 1 << 11;
+        // the absolute-form (or CONNECT) request target other than the SNI, for any target
+        // (never :authority, which is judged as Host is)
+        const LINE_HOST_MISMATCH = 1 << 12;
+        // Host other than :authority on HTTP/2 and HTTP/3, for any target
+        const AUTHORITY_HOST_MISMATCH = 1 << 13;
+        // an authority other than the first one on the connection, as strict_sni_coalescing forbids,
+        // for any target
+        const COALESCING_MISMATCH = 1 << 14;
+        // no SNI in the PROXY protocol header where it is expected, for any target
+        const PROXY_SNI_MISSING = 1 << 15;
+
+        // host, strict rigor only
+        const SNI_MISSING = 1 << 16;
+        const HOST_NOT_GIVEN = 1 << 17;
+
+        // forwarded
+        // X-Forwarded-Host or Forwarded host= other than what Host may be
+        const FORWARDED_MISMATCH = 1 << 18;
+
+        // connect
+        // a CONNECT request, wrong only by strict_sni_connect reject
+        const CONNECT_METHOD = 1 << 19;
+
+        const RFC = Self::HOST_MISSING.bits()
+            | Self::HOST_MALFORMED.bits()
+            | Self::HOST_LINE_MISMATCH.bits();
+        const PORT = Self::HOST_UNPARSABLE.bits()
+            | Self::LINE_UNPARSABLE.bits()
+            | Self::HEADER_PORT_MISMATCH.bits()
+            | Self::LINE_PORT_MISMATCH.bits();
+        // failing the host check for any target and rigor
+        const CANONICAL = Self::HOST_UNPARSABLE.bits()
+            | Self::LINE_UNPARSABLE.bits()
+            | Self::NAME_INVALID.bits()
+            | Self::AUTHORITY_HOST_MISMATCH.bits()
+            | Self::PROXY_SNI_MISSING.bits();
+        const STRICT = Self::SNI_MISSING.bits() | Self::HOST_NOT_GIVEN.bits();
+        const FORWARDED = Self::FORWARDED_MISMATCH.bits();
+        const CONNECT = Self::CONNECT_METHOD.bits();
+    }
+}
+
+impl Reasons {
+    // the names of the single reasons, like "sni_host_mismatch,sni_missing"
+    // (the groups are never named, as the single ones before them take their bits)
+    fn names(&self) -> String {
+        let names: Vec<String> = self
+            .iter_names()
+            .map(|(name, _)| name.to_ascii_lowercase())
+            .collect();
+        names.join(",")
+    }
 }
 // impl Drop for Analysis {
 //     fn drop(&mut self) {
@@ -295,12 +1104,70 @@ pub struct Analysis {
 
 #[derive(Debug)]
 struct Validator<'a> {
+    rfc_mode: Option<()>,
     port_mode: Option<()>,
     host_mode: Option<&'a HostCheckRigor>,
+    host_target: &'a HostCheckTarget,
+    aliases: &'a [HostAlias],
+    exempt: &'a [Cidr],
+    port_reject: &'a RejectAction,
+    host_reject: &'a RejectAction,
+    on_mismatch: &'a MismatchAction,
+    mode: &'a ValidationMode,
+    log_level: Option<ngx_uint_t>,
+    forwarded: &'a ForwardedCheck,
+    connect: &'a ConnectPolicy,
+    coalescing: &'a CoalescingPolicy,
+}
+
+// the client connected to the PROXY protocol sender over TLS
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+// per worker process, so that a scan cannot flood the error log
+static VIOLATION_LOG_LIMITER: RateLimiter = RateLimiter::new(VIOLATION_LOG_LINES_PER_SECOND);
+const VIOLATION_LOG_LINES_PER_SECOND: u32 = 10;
+
+// the check a request failed
+#[derive(Debug, Clone, Copy)]
+enum Violation {
+    Rfc,
+    Port,
+    Host,
+    Forwarded,
+    Connect,
+}
+
+impl Violation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Violation::Rfc => "rfc",
+            Violation::Port => "port",
+            Violation::Host => "host",
+            Violation::Forwarded => "forwarded",
+            Violation::Connect => "connect",
+        }
+    }
+}
+
+// a failed check, let through if the client is exempt
+#[derive(Debug, Clone, Copy)]
+struct Failure {
+    violation: Violation,
+    exempt: bool,
+}
+
+enum Rejection<'a> {
+    Reject(&'a RejectAction),
+    // with the response when no redirect can be made
+    Redirect(ngx_uint_t, &'a RejectAction),
 }
 
 impl<'a> From<&'a ValidationConfig> for Validator<'a> {
     fn from(conf: &'a ValidationConfig) -> Self {
+        let rfc_mode = match &conf.rfc_mode {
+            CheckSwitch::On(()) => Some(()),
+            _ => None,
+        };
         let port_mode = match &conf.port_mode {
             CheckSwitch::On(()) => Some(()),
             _ => None,
@@ -309,32 +1176,231 @@ impl<'a> From<&'a ValidationConfig> for Validator<'a> {
             CheckSwitch::On(rigor) => Some(rigor),
             _ => None,
         };
+        let host_target = conf.host_target.as_ref().unwrap_or(&HostCheckTarget::Sni);
+        let port_reject = conf
+            .port_reject
+            .as_ref()
+            .unwrap_or(&RejectAction::MISDIRECTED_REQUEST);
+        let host_reject = conf
+            .host_reject
+            .as_ref()
+            .unwrap_or(&RejectAction::MISDIRECTED_REQUEST);
         Validator {
+            rfc_mode,
             port_mode,
             host_mode,
+            host_target,
+            aliases: &conf.aliases,
+            exempt: &conf.exempt,
+            port_reject,
+            host_reject,
+            on_mismatch: conf.on_mismatch.as_ref().unwrap_or(&MismatchAction::Reject),
+            mode: conf.mode.as_ref().unwrap_or(&ValidationMode::Enforce),
+            log_level: conf.log_level,
+            forwarded: conf.forwarded.as_ref().unwrap_or(&ForwardedCheck::Off),
+            connect: conf.connect.as_ref().unwrap_or(&ConnectPolicy::Validate),
+            coalescing: conf.coalescing.as_ref().unwrap_or(&CoalescingPolicy::Allow),
         }
     }
 }
 
 impl Validator<'_> {
-    fn validate(&self, request: &Request, analysis: &Analysis) -> Result<(), HTTPStatus> {
-        let mut succ_flag = true;
+    fn is_aliased(&self, sni: Option<&str>, host: Option<&str>) -> bool {
+        if let (Some(sni), Some(host)) = (sni, host) {
+            return self.aliases.iter().any(|alias| alias.accepts(sni, host));
+        }
+        false
+    }
+    fn rejection(&self, violation: Violation) -> Rejection {
+        // protocol violations are the client's fault, not a misdirection
+        let action = match violation {
+            Violation::Rfc => return Rejection::Reject(&RejectAction::BAD_REQUEST),
+            Violation::Connect => return Rejection::Reject(&RejectAction::NOT_ALLOWED),
+            // a redirect to the SNI would keep the forged header
+            Violation::Forwarded => return Rejection::Reject(self.host_reject),
+            Violation::Port => self.port_reject,
+            Violation::Host => self.host_reject,
+        };
+        match self.on_mismatch {
+            MismatchAction::Reject => Rejection::Reject(action),
+            MismatchAction::Redirect(status) => Rejection::Redirect(*status, action),
+        }
+    }
+    // the headers of a request failing strict_sni_forwarded strip, removed in preaccess
+    fn strips_forwarded(&self, analysis: &Analysis) -> bool {
+        matches!(self.forwarded, ForwardedCheck::Strip)
+            && analysis.reasons.intersects(Reasons::FORWARDED)
+    }
+    // a trusted client, which the failed checks let through
+    fn is_exempt(&self, request: &Request) -> bool {
+        if self.exempt.is_empty() {
+            return false;
+        }
+        RequestExt::connection(request)
+            .and_then(|c| c.remote_addr())
+            .is_some_and(|addr| self.exempt.iter().any(|cidr| cidr.contains(addr)))
+    }
+    // the failed request is logged in either mode; report mode lets it through,
+    // and so does the exemption.
+    fn fail(&self, request: &Request, analysis: &Analysis, failure: Failure) -> Status {
+        let Failure { violation, exempt } = failure;
+        let reasons = self.violation_reasons(analysis, violation);
+        self.log_violation(request, analysis, violation, reasons, exempt);
+        if exempt {
+            count(request, analysis, Outcome::Passed);
+            return Status::NGX_DECLINED;
+        }
+        match self.mode {
+            ValidationMode::Enforce => {
+                count(request, analysis, Outcome::Rejected(reasons));
+                reject(request, analysis, self.rejection(violation))
+            }
+            ValidationMode::Report => {
+                count(request, analysis, Outcome::Reported(reasons));
+                Status::NGX_DECLINED
+            }
+        }
+    }
+    // the reasons of the analysis failing the check
+    fn violation_reasons(&self, analysis: &Analysis, violation: Violation) -> Reasons {
+        match violation {
+            Violation::Rfc => analysis.reasons & Reasons::RFC,
+            Violation::Port => analysis.reasons & Reasons::PORT,
+            Violation::Host => {
+                self.host_reasons(analysis, self.host_mode.unwrap_or(&HostCheckRigor::Normal))
+            }
+            Violation::Forwarded => analysis.reasons & Reasons::FORWARDED,
+            Violation::Connect => analysis.reasons & Reasons::CONNECT,
+        }
+    }
+    // one line per failed request, with the client, server and request added by nginx.
+    fn log_violation(
+        &self,
+        request: &Request,
+        analysis: &Analysis,
+        violation: Violation,
+        reasons: Reasons,
+        exempt: bool,
+    ) {
+        let (mode, default_level) = match self.mode {
+            _ if exempt => ("exempt", NGX_LOG_INFO),
+            ValidationMode::Enforce => ("enforce", NGX_LOG_INFO),
+            ValidationMode::Report => ("report", NGX_LOG_WARN),
+        };
+        let level = self.log_level.unwrap_or(default_level as ngx_uint_t);
+        // a line error_log would drop takes no place from those written
+        if !request.log_enabled(level) {
+            return;
+        }
+        let Some(suppressed) = VIOLATION_LOG_LIMITER.permit(request.get_inner().start_sec) else {
+            return;
+        };
+        let mut msg = format!(
+            "strict_sni violation: mode={} check={} reasons={} sni={} host={} line_authority={} local_port={}",
+            mode,
+            violation.as_str(),
+            reasons.names(),
+            analysis.sni.as_deref().unwrap_or("-"),
+            analysis.header_host.as_deref().unwrap_or("-"),
+            analysis.line_authority.as_deref().unwrap_or("-"),
+            analysis.conn_port.map_or("-".to_string(), |port| port.to_string()),
+        );
+        if suppressed > 0 {
+            msg.push_str(&format!(" suppressed={}", suppressed));
+        }
+        request.log_error(level, &msg);
+    }
+    fn host_check(&self, analysis: &Analysis, rigor: &HostCheckRigor) -> bool {
+        self.host_reasons(analysis, rigor).is_empty()
+    }
+    // the reasons failing the host check
+    fn host_reasons(&self, analysis: &Analysis, rigor: &HostCheckRigor) -> Reasons {
+        let target = Reasons::IP_HOST_MISMATCH
+            | match self.host_target {
+                HostCheckTarget::Sni => Reasons::SNI_HOST_MISMATCH,
+                HostCheckTarget::Cert => Reasons::CERT_HOST_MISMATCH,
+                HostCheckTarget::Server => Reasons::SERVER_HOST_MISMATCH,
+            };
+        let mut reasons = analysis.reasons & Reasons::CANONICAL;
+        if analysis.reasons.intersects(target)
+            && !self.is_aliased(analysis.sni.as_deref(), analysis.host.as_deref())
+        {
+            reasons |= analysis.reasons & target;
+        }
+        if analysis.reasons.contains(Reasons::LINE_HOST_MISMATCH)
+            && !self.is_aliased(analysis.sni.as_deref(), analysis.line_host.as_deref())
+        {
+            reasons |= Reasons::LINE_HOST_MISMATCH;
+        }
+        if let Some(coalesced) = &analysis.coalesced {
+            let shared = coalesced.same_authority
+                || match self.coalescing {
+                    CoalescingPolicy::Off => false,
+                    CoalescingPolicy::SameCert => {
+                        !analysis.reasons.contains(Reasons::CERT_HOST_MISMATCH)
+                    }
+                    CoalescingPolicy::SameServer => coalesced.same_server,
+                    CoalescingPolicy::Allow => true,
+                };
+            if !shared {
+                reasons |= Reasons::COALESCING_MISMATCH;
+            }
+        }
+        if let HostCheckRigor::Strict = rigor {
+            reasons |= analysis.reasons & Reasons::STRICT;
+        }
+        reasons
+    }
+    // the result of every reader: the handlers, the variables and the statistics
+    fn validate(&self, request: &Request, analysis: &Analysis) -> Result<(), Failure> {
+        let result = self.check(analysis).map_err(|violation| Failure {
+            violation,
+            exempt: self.is_exempt(request),
+        });
+        ngx_log_debug_http!(
+            request,
+            "strict_sni validate: rfc:{:?} port:{:?} host:{:?} target:{:?} connect:{:?} -> {:?}",
+            self.rfc_mode,
+            self.port_mode,
+            self.host_mode,
+            self.host_target,
+            self.connect,
+            result
+        );
+        result
+    }
+    // the checks in turn, the first one failed
+    fn check(&self, analysis: &Analysis) -> Result<(), Violation> {
+        if analysis.reasons.contains(Reasons::CONNECT_METHOD) {
+            match self.connect {
+                ConnectPolicy::Validate => {}
+                ConnectPolicy::Exempt => return Ok(()),
+                ConnectPolicy::Reject => return Err(Violation::Connect),
+            }
+        }
+
+        if let Some(()) = &self.rfc_mode {
+            if analysis.reasons.intersects(Reasons::RFC) {
+                return Err(Violation::Rfc);
+            }
+        }
+
         if let Some(()) = &self.port_mode {
-            ngx_log_debug_http!(request, "strict_sni port check activated");
-            succ_flag &= analysis.port_succ_flag;
+            if analysis.reasons.intersects(Reasons::PORT) {
+                return Err(Violation::Port);
+            }
         }
 
         if let Some(rigor) = &self.host_mode {
-            ngx_log_debug_http!(
-                request,
-                "strict_sni host check activated: rigor: {:?}",
-                rigor
-            );
-            succ_flag &= analysis.host_succ_flag;
+            if !self.host_check(analysis, rigor) {
+                return Err(Violation::Host);
+            }
         }
 
-        if !succ_flag {
-            return Err(HTTPStatus::MISDIRECTED_REQUEST);
+        if let ForwardedCheck::Reject = self.forwarded {
+            if analysis.reasons.intersects(Reasons::FORWARDED) {
+                return Err(Violation::Forwarded);
+            }
         }
 
         // for (k, v) in request.headers_in_iterator() {
@@ -354,24 +1420,203 @@ impl Validator<'_> {
     }
 }
 
+// the analysis of the request, made now if not yet
+fn analysis(request: &Request) -> Option<&Analysis> {
+    request.get_ctx::<StrictSniHttpModule>()?.analysis(request)
+}
+
+// the analysis of the request, and the validator of its location
+fn analyzed(request: &Request) -> Option<(Validator, &Analysis)> {
+    let config = request.loc_conf::<StrictSniHttpModule>()?;
+    Some((config.into(), analysis(request)?))
+}
+
+fn verdict(succ_flag: bool) -> Cow<'static, [u8]> {
+    Cow::Borrowed(if succ_flag { b"pass" } else { b"fail" })
+}
+
+// pass or fail, as the location validates the request,
+// or exempt if an exempt client fails
+pub(crate) struct ResultVariable;
+impl VariableGetter for ResultVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_result");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        let (val, analysis) = analyzed(request)?;
+        match val.validate(request, analysis) {
+            Err(Failure { exempt: true, .. }) => Some(Cow::Borrowed(b"exempt")),
+            result => Some(verdict(result.is_ok())),
+        }
+    }
+}
+
+// the check failed first, or empty (for an exempt client too)
+pub(crate) struct ReasonVariable;
+impl VariableGetter for ReasonVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_reason");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        let (val, analysis) = analyzed(request)?;
+        let reason = match val.validate(request, analysis) {
+            Err(Failure {
+                violation,
+                exempt: false,
+            }) => violation.as_str(),
+            _ => "",
+        };
+        Some(Cow::Borrowed(reason.as_bytes()))
+    }
+}
+
+// the port check, whether it is enabled or not; pass for an exempt client
+pub(crate) struct PortResultVariable;
+impl VariableGetter for PortResultVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_port_result");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        let (val, analysis) = analyzed(request)?;
+        Some(verdict(
+            !analysis.reasons.intersects(Reasons::PORT) || val.is_exempt(request),
+        ))
+    }
+}
+
+// the host check, with the normal rigor if it is not enabled; pass for an exempt client
+pub(crate) struct HostResultVariable;
+impl VariableGetter for HostResultVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_host_result");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        let (val, analysis) = analyzed(request)?;
+        let rigor = val.host_mode.unwrap_or(&HostCheckRigor::Normal);
+        Some(verdict(
+            val.host_check(analysis, rigor) || val.is_exempt(request),
+        ))
+    }
+}
+
+pub(crate) struct HeaderHostVariable;
+impl VariableGetter for HeaderHostVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_header_host");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        let analysis = analysis(request)?;
+        Some(Cow::Borrowed(analysis.header_host.as_deref()?.as_bytes()))
+    }
+}
+
+pub(crate) struct HeaderPortVariable;
+impl VariableGetter for HeaderPortVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_header_port");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        let analysis = analysis(request)?;
+        Some(Cow::Owned(analysis.header_port?.to_string().into_bytes()))
+    }
+}
+
+// X-Forwarded-Host as received, empty if it fails the check of strict_sni_forwarded
+pub(crate) struct XForwardedHostVariable;
+impl VariableGetter for XForwardedHostVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_x_forwarded_host");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        forwarded_header(request, "X-Forwarded-Host")
+    }
+}
+
+// Forwarded as received, empty if it fails the check of strict_sni_forwarded
+pub(crate) struct ForwardedVariable;
+impl VariableGetter for ForwardedVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_forwarded");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        forwarded_header(request, "Forwarded")
+    }
+}
+
+// for proxy_set_header, which drops a header of an empty value
+fn forwarded_header<'a>(request: &'a Request, name: &str) -> Option<Cow<'a, [u8]>> {
+    let (val, analysis) = analyzed(request)?;
+    if !matches!(val.forwarded, ForwardedCheck::Off)
+        && analysis.reasons.intersects(Reasons::FORWARDED)
+    {
+        return Some(Cow::Borrowed(b""));
+    }
+    match &request.header_values(name)[..] {
+        [] => None,
+        [value] => Some(Cow::Borrowed(value.as_bytes())),
+        values => {
+            let values: Vec<&[u8]> = values.iter().map(|value| value.as_bytes()).collect();
+            Some(Cow::Owned(values.join(&b", "[..])))
+        }
+    }
+}
+
+// host[:port] of an absolute-form request target, or of the authority-form one of CONNECT
+pub(crate) struct LineAuthorityVariable;
+impl VariableGetter for LineAuthorityVariable {
+    const NAME: ngx_str_t = ngx_string!("strict_sni_line_authority");
+    fn get(request: &Request) -> Option<Cow<'_, [u8]>> {
+        let analysis = analysis(request)?;
+        Some(Cow::Borrowed(
+            analysis.line_authority.as_deref()?.as_bytes(),
+        ))
+    }
+}
+
+// the hosts of X-Forwarded-Host and Forwarded, None for a header not parsed
+fn forwarded_hosts(request: &Request) -> Vec<Option<String>> {
+    let mut hosts = Vec::new();
+    for value in request.header_values("X-Forwarded-Host") {
+        match value.to_str() {
+            Ok(value) => hosts.extend(value.split(',').map(|host| Some(host.trim().to_string()))),
+            Err(_) => hosts.push(None),
+        }
+    }
+    for value in request.header_values("Forwarded") {
+        match value
+            .to_str()
+            .ok()
+            .and_then(|value| parse_forwarded_hosts(value).ok())
+        {
+            Some(values) => hosts.extend(values.into_iter().map(Some)),
+            None => hosts.push(None),
+        }
+    }
+    hosts
+}
+
 fn extract_header_host_port(hhs: &str) -> Option<(&str, Option<u16>)> {
     parse_host_header(hhs).ok()
 }
 
-fn extract_line_host_port(rls: &str) -> Option<(&str, Option<u16>)> {
-    if let Ok((method, uri, _signature)) = parse_request_line(rls) {
-        // if method == connect, then it is new style hop-by-hop request, and then uri not mean the server's host name.
-        // if != , then it is old style absolute form request, and then uri mean proxy, and nginx behave as proxy only for internal virtual server.
+// whether the method is CONNECT, and the host and port of the request target:
+// the authority-form one of CONNECT (None if it does not parse), or the absolute-form one.
+fn extract_line_host_port(rls: &str) -> (bool, Option<(&str, Option<u16>)>) {
+    // CONNECT names the tunnel endpoint by host:port, which is no URI to parse_request_line.
+    let mut iter = rls.split(' ').filter(|s| !s.is_empty());
+    if iter
+        .next()
+        .is_some_and(|method| method.eq_ignore_ascii_case("CONNECT"))
+    {
+        let hp = iter
+            .next()
+            .and_then(|target| parse_authority_form(target).ok())
+            .map(|(host, port)| (host, Some(port)));
+        return (true, hp);
+    }
+    if let Ok((_method, uri, _signature)) = parse_request_line(rls) {
+        // the absolute-form request is an old style proxy request, and nginx behaves as proxy only for internal virtual server.
         // here we don't check http version, since there would be a lot of undocumented extension implementation.
-        if !method.eq_ignore_ascii_case("CONNECT") {
-            if let Some(auth) = uri.authority() {
-                let host = auth.host();
-                let port = auth.port_to_u16().unwrap_or(None);
-                return Some((host, port));
-            }
+        if let Some(auth) = uri.authority() {
+            let host = auth.host();
+            let port = auth.port_to_u16().unwrap_or(None);
+            return (false, Some((host, port)));
         }
     }
-    None
+    (false, None)
+}
+
+// the ports of the server among those of every server block
+fn server_ports<S>(ports: &[(*const S, u16)], server: *const S) -> Vec<u16> {
+    ports
+        .iter()
+        .filter(|(srv, _)| !server.is_null() && ptr::eq(*srv, server))
+        .map(|(_, port)| *port)
+        .collect()
 }
 
 fn validate_port(conn_port: Option<u16>, req_port: Option<u16>, scheme_port: Option<u16>) -> bool {
@@ -408,48 +1653,6 @@ fn validate_port(conn_port: Option<u16>, req_port: Option<u16>, scheme_port: Opt
 //     }
 // }
 
-// struct SslInfo<'a> {
-//     sni: Option<&'a str>,
-//     //cert: &'a mut X509,
-// }
-// impl<'a> SslInfo<'a> {
-//     fn check_host(&'a self, name: &str) -> bool {
-//         let a: &[raw::c_char] = name;
-//         X509_check_host(*cert, a.as_ptr(), a.len(), 0, std::ptr::null_mut());
-//         true
-//     }
-// }
-
-// fn get_ssl_info<'a>(request: &Request, conn: &'a ngx_connection_t) -> Option<SslInfo<'a>> {
-//     ngx_log_debug_http!(request, "load conn");
-//     if let Some(ssl) = unsafe { conn.ssl.as_ref() } {
-//         // if let Some(ssl_conn) = unsafe { ssl.connection.as_ref() } {
-//         //     if let Some(cert) = unsafe { SSL_get_certificate(ssl_conn).as_ref() } {
-//         //         get_cert_fingerprint(cert);
-//         //     }
-//         // }
-//         // let a: ngx::ffi::ngx_http_connection_t;
-
-//         let mut name = ngx_null_string!();
-//         let sni = if unsafe {
-//             // here we get str alloc in conn pool: this means &str lifetime is same with conn.
-//             ngx_ssl_get_server_name(conn, conn.pool, &mut name)
-//         } == Status::NGX_OK.into()
-//         {
-//             // ngx_log_debug_http!(request, "strict_sni ssl_servername \"{}\"", sni);
-//             // here unsafely expand the lifetime, but it is actually vaild.
-//             let sni: &'a str = unsafe { (name.to_str() as *const str).as_ref().unwrap() };
-//             Some(sni)
-//         } else {
-//             // ngx_log_debug_http!(request, "strict_sni ssl_servername nullptr");
-//             None
-//         };
-//         Some(SslInfo { sni })
-//     } else {
-//         None
-//     }
-// }
-
 // fn get_scheme_str<'a>(request: &'a Request) -> Option<&'a str> {
 //     let inner = request.get_inner();
 //     if !(inner.schema_start.is_null() || inner.schema_end.is_null()) {
@@ -482,6 +1685,118 @@ fn validate_port(conn_port: Option<u16>, req_port: Option<u16>, scheme_port: Opt
 //     None
 // }
 
-fn eq_host_name(host1: &str, host2: &str) -> bool {
-    host1.eq_ignore_ascii_case(host2)
+#[cfg(test)]
+mod test {
+    use core::cell::Cell;
+
+    use core::ptr;
+
+    use super::{extract_line_host_port, server_ports, Analysis, Reasons, Validator, Violation};
+    use crate::{CheckSwitch, ConnectPolicy, HostCheckRigor, ValidationConfig};
+
+    #[test]
+    fn extract_line_host_port_test() {
+        let extract = extract_line_host_port;
+        assert_eq!(
+            extract("CONNECT example.com:443 HTTP/1.1"),
+            (true, Some(("example.com", Some(443))))
+        );
+        assert_eq!(
+            extract("CONNECT [::1]:8443 HTTP/1.1"),
+            (true, Some(("[::1]", Some(8443))))
+        );
+        assert_eq!(
+            extract("connect example.com:443 HTTP/1.1"),
+            (true, Some(("example.com", Some(443))))
+        );
+        // authority-form only: no port, or a URI, does not parse
+        assert_eq!(extract("CONNECT example.com HTTP/1.1"), (true, None));
+        assert_eq!(
+            extract("CONNECT https://example.com:443/ HTTP/1.1"),
+            (true, None)
+        );
+        assert_eq!(extract("CONNECT HTTP/1.1"), (true, None));
+        // extended CONNECT names the target by :authority, and the line by :path
+        assert_eq!(extract("CONNECT /chat HTTP/2.0"), (true, None));
+        assert_eq!(
+            extract("GET https://example.com:8443/ HTTP/1.1"),
+            (false, Some(("example.com", Some(8443))))
+        );
+        assert_eq!(extract("GET / HTTP/1.1"), (false, None));
+    }
+
+    #[test]
+    fn server_ports_test() {
+        // the server of the QUIC listener, also on 4439 and 8443 over TCP, and another one
+        let (quic, other) = (0u8, 0u8);
+        let (quic, other) = (&quic as *const u8, &other as *const u8);
+        let ports = [(quic, 4439), (other, 4433), (other, 4439), (quic, 8443)];
+        assert_eq!(server_ports(&ports, quic), vec![4439, 8443]);
+        assert!(!server_ports(&ports, quic).contains(&4433));
+        assert_eq!(server_ports(&ports, other), vec![4433, 4439]);
+        assert!(server_ports(&ports, ptr::null()).is_empty());
+    }
+
+    fn analysis(reasons: Reasons) -> Analysis {
+        Analysis {
+            sni: Some("example.com".to_string()),
+            host: Some("example.com".to_string()),
+            line_host: Some("example.com".to_string()),
+            header_host: None,
+            header_port: None,
+            line_authority: Some("example.com:443".to_string()),
+            conn_port: Some(443),
+            reasons,
+            sni_source: None,
+            coalesced: None,
+            first_authority: None,
+            counted: Cell::new(false),
+        }
+    }
+
+    #[test]
+    fn validate_connect_test() {
+        let check = |connect, reasons| {
+            let conf = ValidationConfig {
+                rfc_mode: CheckSwitch::On(()),
+                port_mode: CheckSwitch::On(()),
+                host_mode: CheckSwitch::On(HostCheckRigor::Normal),
+                connect,
+                ..Default::default()
+            };
+            let val: Validator = (&conf).into();
+            val.check(&analysis(reasons))
+        };
+        let valid = Reasons::CONNECT_METHOD;
+        let other_port = Reasons::CONNECT_METHOD | Reasons::LINE_PORT_MISMATCH;
+        let other_host = Reasons::CONNECT_METHOD | Reasons::LINE_HOST_MISMATCH;
+        let unparsable = Reasons::CONNECT_METHOD | Reasons::LINE_UNPARSABLE;
+
+        // validate (the default) checks the target like an absolute-form one
+        for connect in [None, Some(ConnectPolicy::Validate)] {
+            assert!(check(connect.clone(), valid).is_ok());
+            assert!(matches!(
+                check(connect.clone(), other_port),
+                Err(Violation::Port)
+            ));
+            assert!(matches!(
+                check(connect.clone(), other_host),
+                Err(Violation::Host)
+            ));
+            assert!(matches!(check(connect, unparsable), Err(Violation::Port)));
+        }
+        for reasons in [valid, other_port, other_host, unparsable] {
+            assert!(check(Some(ConnectPolicy::Exempt), reasons).is_ok());
+            assert!(matches!(
+                check(Some(ConnectPolicy::Reject), reasons),
+                Err(Violation::Connect)
+            ));
+        }
+        // the policy is not for other methods
+        assert!(check(Some(ConnectPolicy::Reject), Reasons::empty()).is_ok());
+        assert!(matches!(
+            check(Some(ConnectPolicy::Exempt), Reasons::LINE_HOST_MISMATCH),
+            Err(Violation::Host)
+        ));
+    }
 }
@@ -0,0 +1,99 @@
+use core::slice;
+
+use ngx::ffi::{
+    ngx_array_t, ngx_conf_log_error, ngx_conf_t, ngx_http_conf_addr_t, ngx_http_conf_ctx_t,
+    ngx_http_conf_port_t, ngx_http_core_loc_conf_t, ngx_http_core_main_conf_t,
+    ngx_http_core_module, ngx_http_core_srv_conf_t, ngx_str_t, ngx_uint_t, NGX_LOG_EMERG,
+};
+#[cfg(ngx_quic)]
+use ngx::ffi::{ngx_int_t, SOCK_STREAM};
+
+pub trait ConfExt {
+    // arguments of the directive under processing, excluding the directive name.
+    fn args(&self) -> &[ngx_str_t];
+    // the core location configuration on the level under processing,
+    // e.g. to set the content handler.
+    fn core_loc_conf(&mut self) -> Option<&mut ngx_http_core_loc_conf_t>;
+    // ngx_conf_log_error() at emerg, which adds the file and the line of the directive
+    fn log_emerg(&self, msg: &str);
+    // the TCP ports each server block listens on, by the listen directives:
+    // known only until the http block is done with, and not for a server without listen.
+    fn server_tcp_ports(&self) -> Vec<(*const ngx_http_core_srv_conf_t, u16)>;
+}
+
+impl ConfExt for ngx_conf_t {
+    fn args(&self) -> &[ngx_str_t] {
+        if let Some(args) = unsafe { self.args.as_ref() } {
+            if args.nelts > 0 && !args.elts.is_null() {
+                let all =
+                    unsafe { slice::from_raw_parts(args.elts as *const ngx_str_t, args.nelts) };
+                return &all[1..];
+            }
+        }
+        &[]
+    }
+    fn core_loc_conf(&mut self) -> Option<&mut ngx_http_core_loc_conf_t> {
+        let ctx = unsafe { self.ctx.cast::<ngx_http_conf_ctx_t>().as_ref() }?;
+        if ctx.loc_conf.is_null() {
+            return None;
+        }
+        let index = unsafe { ngx_http_core_module.ctx_index };
+        unsafe {
+            (*ctx.loc_conf.add(index))
+                .cast::<ngx_http_core_loc_conf_t>()
+                .as_mut()
+        }
+    }
+    fn log_emerg(&self, msg: &str) {
+        unsafe {
+            ngx_conf_log_error(
+                NGX_LOG_EMERG as ngx_uint_t,
+                self as *const _ as *mut _,
+                0,
+                c"%*s".as_ptr(),
+                msg.len(),
+                msg.as_ptr(),
+            );
+        }
+    }
+    fn server_tcp_ports(&self) -> Vec<(*const ngx_http_core_srv_conf_t, u16)> {
+        let mut ports = Vec::new();
+        let Some(ctx) = (unsafe { self.ctx.cast::<ngx_http_conf_ctx_t>().as_ref() }) else {
+            return ports;
+        };
+        if ctx.main_conf.is_null() {
+            return ports;
+        }
+        let index = unsafe { ngx_http_core_module.ctx_index };
+        let cmcf = unsafe {
+            (*ctx.main_conf.add(index))
+                .cast::<ngx_http_core_main_conf_t>()
+                .as_ref()
+        };
+        let Some(conf_ports) = cmcf.and_then(|cmcf| unsafe { cmcf.ports.as_ref() }) else {
+            return ports;
+        };
+        for port in unsafe { array_elts::<ngx_http_conf_port_t>(conf_ports) } {
+            // every port is TCP before QUIC (and its type) came in 1.25.0
+            #[cfg(ngx_quic)]
+            if port.type_ != SOCK_STREAM as ngx_int_t {
+                continue;
+            }
+            for addr in unsafe { array_elts::<ngx_http_conf_addr_t>(&port.addrs) } {
+                for &server in unsafe { array_elts::<*mut ngx_http_core_srv_conf_t>(&addr.servers) }
+                {
+                    ports.push((server.cast_const(), port.port));
+                }
+            }
+        }
+        ports
+    }
+}
+
+// the elements of an array of T
+unsafe fn array_elts<T>(array: &ngx_array_t) -> &[T] {
+    if array.nelts == 0 || array.elts.is_null() {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(array.elts as *const T, array.nelts) }
+}
@@ -0,0 +1,79 @@
+use core::mem::{size_of, zeroed};
+use core::ptr::slice_from_raw_parts;
+
+use ngx::{
+    ffi::{
+        ngx_conf_t, ngx_http_compile_complex_value, ngx_http_compile_complex_value_t,
+        ngx_http_complex_value, ngx_http_complex_value_t, ngx_int_t, ngx_pcalloc, ngx_pnalloc,
+        ngx_str_t, NGX_OK,
+    },
+    http::Request,
+};
+
+// compiled in the configuration pool, so it lives as long as the configuration does.
+#[derive(Debug, Clone, Copy)]
+pub struct ComplexValue(*mut ngx_http_complex_value_t);
+
+pub struct ComplexValueCompileError;
+
+impl ComplexValue {
+    pub fn get<'a>(&self, req: &'a Request) -> Option<&'a [u8]> {
+        let mut value = ngx_str_t {
+            len: 0,
+            data: core::ptr::null_mut(),
+        };
+        let r = unsafe {
+            ngx_http_complex_value(req.get_inner() as *const _ as *mut _, self.0, &mut value)
+        };
+        if r != NGX_OK as ngx_int_t {
+            return None;
+        }
+        if value.len == 0 {
+            return Some(&[]);
+        }
+        // evaluated into the request pool
+        unsafe { slice_from_raw_parts(value.data, value.len).as_ref() }
+    }
+    // a value without variables, built at runtime in the request pool.
+    pub fn constant(req: &Request, value: &[u8]) -> Option<ComplexValue> {
+        let pool = req.get_inner().pool;
+        let cv = unsafe { ngx_pcalloc(pool, size_of::<ngx_http_complex_value_t>()) }
+            as *mut ngx_http_complex_value_t;
+        let data = unsafe { ngx_pnalloc(pool, value.len()) } as *mut u8;
+        if cv.is_null() || data.is_null() {
+            return None;
+        }
+        unsafe {
+            data.copy_from_nonoverlapping(value.as_ptr(), value.len());
+            (*cv).value = ngx_str_t {
+                len: value.len(),
+                data,
+            };
+        }
+        Some(ComplexValue(cv))
+    }
+    pub fn as_ptr(&self) -> *mut ngx_http_complex_value_t {
+        self.0
+    }
+}
+
+pub trait CompileComplexValue {
+    fn compile(&mut self, value: &ngx_str_t) -> Result<ComplexValue, ComplexValueCompileError>;
+}
+impl CompileComplexValue for ngx_conf_t {
+    fn compile(&mut self, value: &ngx_str_t) -> Result<ComplexValue, ComplexValueCompileError> {
+        let cv = unsafe { ngx_pcalloc(self.pool, size_of::<ngx_http_complex_value_t>()) }
+            as *mut ngx_http_complex_value_t;
+        if cv.is_null() {
+            return Err(ComplexValueCompileError);
+        }
+        let mut ccv: ngx_http_compile_complex_value_t = unsafe { zeroed() };
+        ccv.cf = self;
+        ccv.value = value as *const _ as *mut _;
+        ccv.complex_value = cv;
+        if unsafe { ngx_http_compile_complex_value(&mut ccv) } != NGX_OK as ngx_int_t {
+            return Err(ComplexValueCompileError);
+        }
+        Ok(ComplexValue(cv))
+    }
+}
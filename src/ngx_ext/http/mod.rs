@@ -1,2 +1,3 @@
+pub mod complex_value;
 pub mod request;
 pub mod variable;
@@ -1,26 +1,72 @@
+use core::ffi::{c_char, c_uint, c_void};
+use core::mem::{size_of, zeroed};
+use core::net::IpAddr;
+use core::ptr::{addr_of, addr_of_mut, copy_nonoverlapping, null_mut};
+
 use ngx::{
     core::NgxStr,
-    ffi::{ngx_connection_t, ngx_inet_get_port},
+    ffi::{
+        getsockname, ngx_connection_t, ngx_http_core_module, ngx_http_core_srv_conf_t,
+        ngx_http_finalize_request, ngx_http_request_t, ngx_http_send_response, ngx_inet_get_port,
+        ngx_int_t, ngx_list_part_t, ngx_log_error_core, ngx_palloc, ngx_proxy_protocol_t,
+        ngx_str_t, ngx_table_elt_t, ngx_uint_t, sockaddr, sockaddr_in, sockaddr_in6,
+        sockaddr_storage, socklen_t, SSL_get_certificate, X509_check_host, AF_INET, AF_INET6,
+        NGX_HTTP_VERSION_20, SOCK_DGRAM, SSL, X509_CHECK_FLAG_NO_PARTIAL_WILDCARDS,
+    },
     http::{HttpModule, HttpModuleSkel, InitConfSetting, MergeConfSetting, Request},
     module::Module,
 };
 
-use crate::ngx_ext::str::try_to_ref;
+#[cfg(ngx_proxy_protocol_tlv)]
+use ngx::ffi::{ngx_proxy_protocol_get_tlv, NGX_OK};
+
+use crate::ngx_ext::{http::complex_value::ComplexValue, str::try_to_ref};
 
 pub trait RequestExt {
     // note: you can elide lifetime parameter if the returned ref's lifetime is same to self.
     // https://doc.rust-lang.org/nomicon/lifetime-elision.html
     fn host_header(&self) -> Option<&NgxStr>;
+    // every header of the name (case-insensitive), in the order received
+    fn header_values(&self, name: &str) -> Vec<&NgxStr>;
     fn request_line(&self) -> Option<&NgxStr>;
+    // :authority of HTTP/2 and HTTP/3, which nginx keeps apart from the Host header
+    fn authority(&self) -> Option<&NgxStr>;
+    fn unparsed_uri(&self) -> Option<&NgxStr>;
+    fn http_version(&self) -> ngx_uint_t;
     fn connection(&self) -> Option<&Connection>;
+    // the virtual server the request is routed to (by Host)
+    fn core_srv_conf(&self) -> Option<&ngx_http_core_srv_conf_t>;
+    // the virtual server the connection was set up for (by SNI on TLS handshake)
+    fn connection_core_srv_conf(&self) -> Option<&ngx_http_core_srv_conf_t>;
 
     fn main_conf<M: HttpModule>(&self) -> Option<&<M::MainConfSetting as InitConfSetting>::Conf>;
     fn srv_conf<M: HttpModule>(&self) -> Option<&<M::SrvConfSetting as MergeConfSetting>::Conf>;
     fn loc_conf<M: HttpModule>(&self) -> Option<&<M::LocConfSetting as MergeConfSetting>::Conf>;
+    // the location conf of the server level, as loc_conf is before any location is found
+    fn server_loc_conf<M: HttpModule>(
+        &self,
+    ) -> Option<&<M::LocConfSetting as MergeConfSetting>::Conf>;
     fn get_ctx<M: HttpModule>(&self) -> Option<&M::Ctx>;
     fn set_ctx<M: HttpModule>(&self, ctx: &M::Ctx);
 
     fn is_internal(&self) -> bool;
+
+    // drops every header of the names (case-insensitive) from headers_in; false if out of memory
+    fn remove_headers(&mut self, names: &[&str]) -> bool;
+
+    // whether the request log writes a line of the level (error_log)
+    fn log_enabled(&self, level: ngx_uint_t) -> bool;
+    // ngx_log_error() on the request log, which adds the client, server and request context
+    fn log_error(&self, level: ngx_uint_t, msg: &str);
+
+    // same as the return directive with a text, or with a URL for 301..308
+    fn send_response(
+        &self,
+        status: ngx_uint_t,
+        content_type: Option<&ngx_str_t>,
+        body: &ComplexValue,
+    ) -> ngx_int_t;
+    fn finalize(&self, rc: ngx_int_t);
 }
 
 impl RequestExt for Request {
@@ -32,10 +78,49 @@ impl RequestExt for Request {
             None
         }
     }
+    fn header_values(&self, name: &str) -> Vec<&NgxStr> {
+        let mut values = Vec::new();
+        let mut part = &self.get_inner().headers_in.headers.part as *const ngx_list_part_t;
+        while let Some(p) = unsafe { part.as_ref() } {
+            let elts = p.elts as *const ngx_table_elt_t;
+            for i in 0..p.nelts {
+                let elt = unsafe { &*elts.add(i) };
+                if try_to_ref(elt.key)
+                    .as_bytes()
+                    .eq_ignore_ascii_case(name.as_bytes())
+                {
+                    values.push(try_to_ref(elt.value));
+                }
+            }
+            part = p.next;
+        }
+        values
+    }
     fn request_line(&self) -> Option<&NgxStr> {
         let inner = self.get_inner();
         Some(try_to_ref(inner.request_line))
     }
+    fn authority(&self) -> Option<&NgxStr> {
+        let inner = self.get_inner();
+        // host_start..host_end is the host of the request line on HTTP/1.x
+        if inner.http_version < NGX_HTTP_VERSION_20 as ngx_uint_t
+            || inner.host_start.is_null()
+            || inner.host_end.is_null()
+        {
+            return None;
+        }
+        Some(try_to_ref(ngx_str_t {
+            len: unsafe { inner.host_end.offset_from(inner.host_start) } as usize,
+            data: inner.host_start,
+        }))
+    }
+    fn unparsed_uri(&self) -> Option<&NgxStr> {
+        let inner = self.get_inner();
+        Some(try_to_ref(inner.unparsed_uri))
+    }
+    fn http_version(&self) -> ngx_uint_t {
+        self.get_inner().http_version
+    }
 
     fn main_conf<M: HttpModule>(&self) -> Option<&<M::MainConfSetting as InitConfSetting>::Conf> {
         self.get_module_main_conf::<<M::MainConfSetting as InitConfSetting>::Conf>(
@@ -52,6 +137,22 @@ impl RequestExt for Request {
             unsafe { HttpModuleSkel::<M>::SELF.to_ref() }.inner(),
         )
     }
+    fn server_loc_conf<M: HttpModule>(
+        &self,
+    ) -> Option<&<M::LocConfSetting as MergeConfSetting>::Conf> {
+        let ctx = unsafe { self.core_srv_conf()?.ctx.as_ref() }?;
+        if ctx.loc_conf.is_null() {
+            return None;
+        }
+        let index = unsafe { HttpModuleSkel::<M>::SELF.to_ref() }
+            .inner()
+            .ctx_index;
+        unsafe {
+            (*ctx.loc_conf.add(index))
+                .cast::<<M::LocConfSetting as MergeConfSetting>::Conf>()
+                .as_ref()
+        }
+    }
 
     fn get_ctx<M: HttpModule>(&self) -> Option<&M::Ctx> {
         self.get_module_ctx::<M::Ctx>(unsafe { HttpModuleSkel::<M>::SELF.to_ref() }.inner())
@@ -81,6 +182,17 @@ impl RequestExt for Request {
         }
     }
 
+    fn core_srv_conf(&self) -> Option<&ngx_http_core_srv_conf_t> {
+        core_srv_conf_of(self.get_inner().srv_conf)
+    }
+    fn connection_core_srv_conf(&self) -> Option<&ngx_http_core_srv_conf_t> {
+        // ngx_http_ssl_servername() switches conf_ctx to the server found by SNI,
+        // while Host only switches the request's srv_conf.
+        let hc = unsafe { self.get_inner().http_connection.as_ref() }?;
+        let ctx = unsafe { hc.conf_ctx.as_ref() }?;
+        core_srv_conf_of(ctx.srv_conf)
+    }
+
     // fn connection_mut(&mut self) -> Option<&mut Connection> {
     //     let p = self.connection();
     //     if p.is_null() {
@@ -93,10 +205,196 @@ impl RequestExt for Request {
     fn is_internal(&self) -> bool {
         self.get_inner().internal() != 0
     }
+
+    fn remove_headers(&mut self, names: &[&str]) -> bool {
+        let r = unsafe { &mut *(self as *mut Self).cast::<ngx_http_request_t>() };
+        let mut kept = Vec::new();
+        let mut removed = false;
+        let mut part = &r.headers_in.headers.part as *const ngx_list_part_t;
+        while let Some(p) = unsafe { part.as_ref() } {
+            let elts = p.elts as *const ngx_table_elt_t;
+            for i in 0..p.nelts {
+                let elt = unsafe { elts.add(i).read() };
+                let key = try_to_ref(elt.key).as_bytes();
+                if names
+                    .iter()
+                    .any(|name| key.eq_ignore_ascii_case(name.as_bytes()))
+                {
+                    removed = true;
+                } else {
+                    kept.push(elt);
+                }
+            }
+            part = p.next;
+        }
+        if !removed {
+            return true;
+        }
+        // the rest is copied into a new list as ngx_list_init() makes it, and the old one
+        // is left in the pool, since headers_in.host and the like point into it
+        let nalloc = kept.len().max(1);
+        let elts = unsafe { ngx_palloc(r.pool, nalloc * size_of::<ngx_table_elt_t>()) }
+            .cast::<ngx_table_elt_t>();
+        if elts.is_null() {
+            return false;
+        }
+        unsafe { copy_nonoverlapping(kept.as_ptr(), elts, kept.len()) };
+        let headers = &mut r.headers_in.headers;
+        headers.part.elts = elts.cast();
+        headers.part.nelts = kept.len();
+        headers.part.next = null_mut();
+        headers.nalloc = nalloc;
+        headers.last = addr_of_mut!(headers.part);
+        true
+    }
+
+    fn log_enabled(&self, level: ngx_uint_t) -> bool {
+        let c = unsafe { self.get_inner().connection.as_ref() };
+        c.and_then(|c| unsafe { c.log.as_ref() })
+            .is_some_and(|log| log.log_level >= level)
+    }
+    fn log_error(&self, level: ngx_uint_t, msg: &str) {
+        let c = unsafe { self.get_inner().connection.as_ref() };
+        if let Some(log) = c.and_then(|c| unsafe { c.log.as_mut() }) {
+            if log.log_level >= level {
+                unsafe {
+                    ngx_log_error_core(level, log, 0, c"%*s".as_ptr(), msg.len(), msg.as_ptr());
+                }
+            }
+        }
+    }
+
+    fn send_response(
+        &self,
+        status: ngx_uint_t,
+        content_type: Option<&ngx_str_t>,
+        body: &ComplexValue,
+    ) -> ngx_int_t {
+        unsafe {
+            ngx_http_send_response(
+                self.get_inner() as *const _ as *mut _,
+                status,
+                content_type.map_or(null_mut(), |ct| ct as *const _ as *mut _),
+                body.as_ptr(),
+            )
+        }
+    }
+    fn finalize(&self, rc: ngx_int_t) {
+        unsafe { ngx_http_finalize_request(self.get_inner() as *const _ as *mut _, rc) }
+    }
+}
+
+fn core_srv_conf_of<'a>(srv_conf: *mut *mut c_void) -> Option<&'a ngx_http_core_srv_conf_t> {
+    if srv_conf.is_null() {
+        return None;
+    }
+    let index = unsafe { ngx_http_core_module.ctx_index };
+    unsafe {
+        (*srv_conf.add(index))
+            .cast::<ngx_http_core_srv_conf_t>()
+            .as_ref()
+    }
+}
+
+// the IP of an AF_INET or AF_INET6 address (not of a unix domain socket)
+fn sockaddr_ip(sa: *const sockaddr, socklen: socklen_t) -> Option<IpAddr> {
+    let family = unsafe { sa.as_ref() }?.sa_family as u32;
+    let socklen = socklen as usize;
+    let ip = if family == AF_INET as u32 && socklen >= size_of::<sockaddr_in>() {
+        let sin = sa.cast::<sockaddr_in>();
+        let octets = unsafe { addr_of!((*sin).sin_addr).cast::<[u8; 4]>().read_unaligned() };
+        IpAddr::from(octets)
+    } else if family == AF_INET6 as u32 && socklen >= size_of::<sockaddr_in6>() {
+        let sin6 = sa.cast::<sockaddr_in6>();
+        let octets = unsafe {
+            addr_of!((*sin6).sin6_addr)
+                .cast::<[u8; 16]>()
+                .read_unaligned()
+        };
+        IpAddr::from(octets)
+    } else {
+        return None;
+    };
+    Some(ip.to_canonical())
 }
 
 pub struct Connection(ngx_connection_t);
 impl Connection {
+    pub fn is_ssl(&self) -> bool {
+        !self.0.ssl.is_null()
+    }
+    pub fn local_addr(&self) -> Option<IpAddr> {
+        let addr = sockaddr_ip(self.0.local_sockaddr, self.0.local_socklen)?;
+        if !addr.is_unspecified() {
+            return Some(addr);
+        }
+        // a wildcard listen knows its address only by getsockname(), as $server_addr does;
+        // asked into a copy here, as the connection is not ours to change
+        let mut local: sockaddr_storage = unsafe { zeroed() };
+        let mut len = size_of::<sockaddr_storage>() as socklen_t;
+        if unsafe { getsockname(self.0.fd, addr_of_mut!(local).cast(), &mut len) } != 0 {
+            return None;
+        }
+        sockaddr_ip(addr_of!(local).cast(), len)
+    }
+    // the client, as rewritten by the realip module in post_read
+    // (so the one of the ban and the exemptions only from preaccess on)
+    pub fn remote_addr(&self) -> Option<IpAddr> {
+        sockaddr_ip(self.0.sockaddr, self.0.socklen)
+    }
+    // the OpenSSL object of a TLS connection
+    pub fn ssl_connection(&self) -> Option<*mut SSL> {
+        let ssl = unsafe { self.0.ssl.as_ref() }?;
+        (!ssl.connection.is_null()).then_some(ssl.connection)
+    }
+    // whether the certificate served on this connection covers the name,
+    // in the same manner as the client verifies it (X509_check_host).
+    // None if there is no such certificate.
+    pub fn certificate_covers(&self, name: &str) -> Option<bool> {
+        let ssl = self.ssl_connection()?;
+        // the certificate selected for the SNI, owned by the SSL_CTX
+        let cert = unsafe { SSL_get_certificate(ssl) };
+        if cert.is_null() {
+            return None;
+        }
+        let r = unsafe {
+            X509_check_host(
+                cert,
+                name.as_ptr() as *const c_char,
+                name.len(),
+                X509_CHECK_FLAG_NO_PARTIAL_WILDCARDS as c_uint,
+                null_mut(),
+            )
+        };
+        Some(r == 1)
+    }
+    // the PROXY protocol header the connection was accepted with
+    pub fn proxy_protocol(&self) -> Option<&ProxyProtocol> {
+        unsafe { self.0.proxy_protocol.cast::<ProxyProtocol>().as_ref() }
+    }
+    // a TLV of the PROXY protocol v2 header, by the name nginx gives it (e.g. "authority", "ssl")
+    #[cfg(ngx_proxy_protocol_tlv)]
+    pub fn proxy_protocol_tlv(&self, name: &str) -> Option<&[u8]> {
+        self.proxy_protocol()?;
+        let c = &self.0 as *const _ as *mut ngx_connection_t;
+        let mut name = ngx_str_t {
+            len: name.len(),
+            data: name.as_ptr() as *mut u8,
+        };
+        let mut value = ngx_str_t {
+            len: 0,
+            data: null_mut(),
+        };
+        if unsafe { ngx_proxy_protocol_get_tlv(c, &mut name, &mut value) } != NGX_OK as ngx_int_t {
+            return None;
+        }
+        Some(try_to_ref(value).as_bytes())
+    }
+    // nginx keeps no TLV before 1.23.2 (so strict_sni_proxy_protocol is refused there)
+    #[cfg(not(ngx_proxy_protocol_tlv))]
+    pub fn proxy_protocol_tlv(&self, _name: &str) -> Option<&[u8]> {
+        None
+    }
     pub fn local_port(&self) -> Option<u16> {
         if let Some(addr) = unsafe { self.0.local_sockaddr.as_mut() } {
             // ngx_inet_get_port is implemented without the use of mutability, so no problem
@@ -107,4 +405,20 @@ impl Connection {
         }
         None
     }
+    // HTTP/3, as nginx listens on UDP for QUIC only (the streams share the listening of it)
+    pub fn is_quic(&self) -> bool {
+        unsafe { self.0.listening.as_ref() }.is_some_and(|ls| ls.type_ == SOCK_DGRAM as i32)
+    }
+}
+
+pub struct ProxyProtocol(ngx_proxy_protocol_t);
+impl ProxyProtocol {
+    // the address and port the client connected to on the proxy
+    pub fn dst_addr(&self) -> Option<IpAddr> {
+        let ip: IpAddr = try_to_ref(self.0.dst_addr).to_str().ok()?.parse().ok()?;
+        Some(ip.to_canonical())
+    }
+    pub fn dst_port(&self) -> Option<u16> {
+        Some(self.0.dst_port).filter(|&port| port != 0)
+    }
 }
@@ -1,9 +1,13 @@
+use std::borrow::Cow;
+
 use core::ptr::slice_from_raw_parts;
 
 use ngx::{
     ffi::{
-        ngx_conf_t, ngx_http_get_flushed_variable, ngx_http_get_indexed_variable,
-        ngx_http_get_variable_index, ngx_int_t, ngx_str_t, ngx_uint_t, NGX_ERROR,
+        ngx_conf_t, ngx_http_add_variable, ngx_http_get_flushed_variable,
+        ngx_http_get_indexed_variable, ngx_http_get_variable_index, ngx_http_request_t,
+        ngx_http_variable_value_t, ngx_int_t, ngx_pnalloc, ngx_str_t, ngx_uint_t, NGX_ERROR,
+        NGX_HTTP_VAR_NOCACHEABLE, NGX_OK,
     },
     http::Request,
 };
@@ -58,6 +62,67 @@ impl GetHook for ngx_conf_t {
     }
 }
 
+// a variable provided by the module, the counterpart of VariableHook.
+pub trait VariableGetter {
+    const NAME: ngx_str_t;
+    // None is "not found"; a borrowed value must live as long as the request,
+    // and an owned one is copied into the request pool.
+    fn get(req: &Request) -> Option<Cow<'_, [u8]>>;
+}
+
+pub struct VariableAddError;
+
+pub trait AddVariable {
+    // add in the preconfiguration, before the variables are indexed.
+    fn add_variable<G: VariableGetter>(&mut self) -> Result<(), VariableAddError>;
+}
+impl AddVariable for ngx_conf_t {
+    fn add_variable<G: VariableGetter>(&mut self) -> Result<(), VariableAddError> {
+        let v = unsafe {
+            ngx_http_add_variable(
+                self,
+                &G::NAME as *const _ as *mut _,
+                NGX_HTTP_VAR_NOCACHEABLE as ngx_uint_t,
+            )
+        };
+        let v = unsafe { v.as_mut() }.ok_or(VariableAddError)?;
+        v.get_handler = Some(get_handler::<G>);
+        Ok(())
+    }
+}
+
+unsafe extern "C" fn get_handler<G: VariableGetter>(
+    r: *mut ngx_http_request_t,
+    v: *mut ngx_http_variable_value_t,
+    _data: usize,
+) -> ngx_int_t {
+    let (Some(req), Some(v)) = (unsafe { r.as_ref() }, unsafe { v.as_mut() }) else {
+        return NGX_ERROR as ngx_int_t;
+    };
+    let req = unsafe { Request::from_ngx_http_request(req as *const _ as *mut _) };
+    let data = match G::get(req) {
+        None => {
+            v.set_not_found(1);
+            return NGX_OK as ngx_int_t;
+        }
+        Some(Cow::Borrowed(value)) => (value.as_ptr() as *mut u8, value.len()),
+        Some(Cow::Owned(value)) => {
+            let p = unsafe { ngx_pnalloc(req.get_inner().pool, value.len()) } as *mut u8;
+            if p.is_null() {
+                return NGX_ERROR as ngx_int_t;
+            }
+            unsafe { p.copy_from_nonoverlapping(value.as_ptr(), value.len()) };
+            (p, value.len())
+        }
+    };
+    v.data = data.0;
+    v.set_len(data.1 as _);
+    v.set_valid(1);
+    v.set_no_cacheable(0);
+    v.set_not_found(0);
+    NGX_OK as ngx_int_t
+}
+
 // fn solve_variable_ref_mut<'a>(r: &VariableRef,req:&'a mut Request)->Option<&'a mut [u8]>{
 //     let r = unsafe { ngx_http_get_flushed_variable( req.get_inner() as *const _ as *mut _, r.0) };
 //     if let Some(v) =unsafe{r.as_ref()} {
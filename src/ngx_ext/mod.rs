@@ -1,2 +1,5 @@
+pub mod conf;
 pub mod http;
+pub mod shm;
+pub mod ssl;
 pub mod str;
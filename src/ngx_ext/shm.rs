@@ -0,0 +1,136 @@
+use core::ffi::c_void;
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+use ngx::ffi::{
+    ngx_conf_t, ngx_int_t, ngx_module_t, ngx_pagesize, ngx_shared_memory_add, ngx_shm_zone_t,
+    ngx_shmtx_lock, ngx_shmtx_unlock, ngx_slab_calloc_locked, ngx_slab_free_locked,
+    ngx_slab_pool_t, ngx_str_t, NGX_ERROR, NGX_OK,
+};
+
+// data placed in a shared memory zone.
+// it is created once in the slab pool, and kept over reloads as long as the zone size is.
+pub trait SharedData: Sized {
+    fn create(pool: &LockedSlabPool) -> Option<*mut Self>;
+}
+
+#[derive(Debug)]
+pub struct SharedZone<T: SharedData> {
+    zone: *mut ngx_shm_zone_t,
+    _data: PhantomData<T>,
+}
+
+pub struct SharedZoneAddError;
+
+impl<T: SharedData> SharedZone<T> {
+    // None before the zone is initialized (i.e. during the configuration)
+    pub fn data(&self) -> Option<&T> {
+        let zone = unsafe { self.zone.as_ref() }?;
+        unsafe { zone.data.cast::<T>().as_ref() }
+    }
+    pub fn pool(&self) -> Option<SlabPool> {
+        let zone = unsafe { self.zone.as_ref() }?;
+        let pool = zone.shm.addr.cast::<ngx_slab_pool_t>();
+        if pool.is_null() {
+            None
+        } else {
+            Some(SlabPool(pool))
+        }
+    }
+}
+
+pub struct SlabPool(*mut ngx_slab_pool_t);
+pub struct LockedSlabPool(*mut ngx_slab_pool_t);
+
+impl SlabPool {
+    pub fn lock<R>(&self, f: impl FnOnce(&LockedSlabPool) -> R) -> R {
+        unsafe { ngx_shmtx_lock(&mut (*self.0).mutex) };
+        let r = f(&LockedSlabPool(self.0));
+        unsafe { ngx_shmtx_unlock(&mut (*self.0).mutex) };
+        r
+    }
+}
+
+impl LockedSlabPool {
+    // zeroed, which is a valid initial state for atomics.
+    pub fn calloc<U>(&self, size: usize) -> Option<*mut U> {
+        debug_assert!(size >= size_of::<U>());
+        let p = unsafe { ngx_slab_calloc_locked(self.0, size) };
+        if p.is_null() {
+            None
+        } else {
+            Some(p.cast::<U>())
+        }
+    }
+    // p must be allocated by calloc of the same pool.
+    pub fn free<U>(&self, p: *mut U) {
+        unsafe { ngx_slab_free_locked(self.0, p.cast()) };
+    }
+}
+
+pub trait AddSharedZone {
+    fn add_shared_zone<T: SharedData>(
+        &mut self,
+        name: &ngx_str_t,
+        size: usize,
+        tag: &ngx_module_t,
+    ) -> Result<SharedZone<T>, SharedZoneAddError>;
+}
+
+impl AddSharedZone for ngx_conf_t {
+    fn add_shared_zone<T: SharedData>(
+        &mut self,
+        name: &ngx_str_t,
+        size: usize,
+        tag: &ngx_module_t,
+    ) -> Result<SharedZone<T>, SharedZoneAddError> {
+        if size < 8 * unsafe { ngx_pagesize } {
+            return Err(SharedZoneAddError);
+        }
+        let zone = unsafe {
+            ngx_shared_memory_add(
+                self,
+                name as *const _ as *mut _,
+                size,
+                tag as *const _ as *mut c_void,
+            )
+        };
+        let z = unsafe { zone.as_mut() }.ok_or(SharedZoneAddError)?;
+        if z.init.is_some() {
+            // already added under the same name
+            return Err(SharedZoneAddError);
+        }
+        z.init = Some(init_zone::<T>);
+        Ok(SharedZone {
+            zone,
+            _data: PhantomData,
+        })
+    }
+}
+
+unsafe extern "C" fn init_zone<T: SharedData>(
+    shm_zone: *mut ngx_shm_zone_t,
+    data: *mut c_void,
+) -> ngx_int_t {
+    let Some(zone) = (unsafe { shm_zone.as_mut() }) else {
+        return NGX_ERROR as ngx_int_t;
+    };
+    // reloaded: the data of the old cycle is still there
+    if !data.is_null() {
+        zone.data = data;
+        return NGX_OK as ngx_int_t;
+    }
+    let pool = zone.shm.addr.cast::<ngx_slab_pool_t>();
+    if zone.shm.exists != 0 {
+        zone.data = unsafe { (*pool).data };
+        return NGX_OK as ngx_int_t;
+    }
+    // nobody else uses the zone yet
+    let locked = LockedSlabPool(pool);
+    let Some(p) = T::create(&locked) else {
+        return NGX_ERROR as ngx_int_t;
+    };
+    unsafe { (*pool).data = p.cast() };
+    zone.data = p.cast();
+    NGX_OK as ngx_int_t
+}
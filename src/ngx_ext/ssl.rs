@@ -0,0 +1,91 @@
+use core::ffi::{c_int, c_long, c_void};
+use core::fmt;
+use core::marker::PhantomData;
+use core::ptr::null_mut;
+
+use ngx::ffi::{
+    CRYPTO_get_ex_new_index, SSL_get_ex_data, SSL_set_ex_data, CRYPTO_EX_DATA, CRYPTO_EX_INDEX_SSL,
+};
+
+use crate::ngx_ext::http::request::Connection;
+
+// data kept with the SSL object of a connection, and dropped with it.
+// HTTP/2 streams share it, as their connections are copies of the real one.
+// only an index, so it can be kept in a static whatever T is.
+pub struct SslExData<T> {
+    index: c_int,
+    _data: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for SslExData<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for SslExData<T> {}
+
+impl<T> fmt::Debug for SslExData<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SslExData")
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
+pub struct SslExDataNewError;
+
+impl<T> SslExData<T> {
+    // an index is never given back to OpenSSL, so take one per process, not per load
+    pub fn new() -> Result<Self, SslExDataNewError> {
+        let index = unsafe {
+            CRYPTO_get_ex_new_index(
+                CRYPTO_EX_INDEX_SSL as c_int,
+                0,
+                null_mut(),
+                None,
+                None,
+                Some(free_boxed::<T>),
+            )
+        };
+        if index < 0 {
+            return Err(SslExDataNewError);
+        }
+        Ok(SslExData {
+            index,
+            _data: PhantomData,
+        })
+    }
+    // None on a plain connection, or before set
+    pub fn get<'a>(&self, connection: &'a Connection) -> Option<&'a T> {
+        let ssl = connection.ssl_connection()?;
+        unsafe { SSL_get_ex_data(ssl, self.index).cast::<T>().as_ref() }
+    }
+    // the data already there is kept, and false is returned
+    pub fn set(&self, connection: &Connection, data: T) -> bool {
+        let Some(ssl) = connection.ssl_connection() else {
+            return false;
+        };
+        if !unsafe { SSL_get_ex_data(ssl, self.index) }.is_null() {
+            return false;
+        }
+        let p = Box::into_raw(Box::new(data));
+        if unsafe { SSL_set_ex_data(ssl, self.index, p.cast()) } != 1 {
+            drop(unsafe { Box::from_raw(p) });
+            return false;
+        }
+        true
+    }
+}
+
+unsafe extern "C" fn free_boxed<T>(
+    _parent: *mut c_void,
+    ptr: *mut c_void,
+    _ad: *mut CRYPTO_EX_DATA,
+    _idx: c_int,
+    _argl: c_long,
+    _argp: *mut c_void,
+) {
+    if !ptr.is_null() {
+        drop(unsafe { Box::from_raw(ptr.cast::<T>()) });
+    }
+}
@@ -0,0 +1,113 @@
+use regex::Regex;
+
+use crate::util::canonicalize_host;
+
+// host name pattern, in the manner of server_name
+#[derive(Debug, Clone)]
+pub enum HostPattern {
+    // "example.com"
+    Exact(String),
+    // "*.example.com": any name under example.com, but not example.com itself
+    // (kept as ".example.com")
+    Wildcard(String),
+    // ".example.com": example.com and any name under it
+    Suffix(String),
+    // "~^api\d+\.example\.com$": case-insensitive regex
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+pub struct ParseHostPatternError;
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Result<HostPattern, ParseHostPatternError> {
+        if let Some(re) = pattern.strip_prefix('~') {
+            return Regex::new(&format!("(?i){}", re))
+                .map(HostPattern::Regex)
+                .map_err(|_| ParseHostPatternError);
+        }
+        // names are kept in the canonical form the analysis compares with
+        let canonical = |name: &str| {
+            if name.contains('*') {
+                return Err(ParseHostPatternError);
+            }
+            canonicalize_host(name).map_err(|_| ParseHostPatternError)
+        };
+        if let Some(domain) = pattern.strip_prefix("*.") {
+            Ok(HostPattern::Wildcard(format!(".{}", canonical(domain)?)))
+        } else if let Some(domain) = pattern.strip_prefix('.') {
+            Ok(HostPattern::Suffix(format!(".{}", canonical(domain)?)))
+        } else {
+            Ok(HostPattern::Exact(canonical(pattern)?))
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => name.eq_ignore_ascii_case(exact),
+            HostPattern::Wildcard(dot_domain) => {
+                name.len() > dot_domain.len() && ends_with_ignore_ascii_case(name, dot_domain)
+            }
+            HostPattern::Suffix(dot_domain) => {
+                name.eq_ignore_ascii_case(&dot_domain[1..])
+                    || ends_with_ignore_ascii_case(name, dot_domain)
+            }
+            HostPattern::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+fn ends_with_ignore_ascii_case(name: &str, suffix: &str) -> bool {
+    let (name, suffix) = (name.as_bytes(), suffix.as_bytes());
+    name.len() >= suffix.len() && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+}
+
+// accepted pair of SNI and Host, beyond their exact equality
+#[derive(Debug, Clone)]
+pub struct HostAlias {
+    pub sni: HostPattern,
+    pub host: HostPattern,
+}
+
+impl HostAlias {
+    pub fn accepts(&self, sni: &str, host: &str) -> bool {
+        self.sni.matches(sni) && self.host.matches(host)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HostPattern;
+
+    #[test]
+    fn host_pattern_test() {
+        let exact = HostPattern::parse("Example.com").unwrap();
+        assert!(exact.matches("example.COM"));
+        assert!(!exact.matches("a.example.com"));
+
+        let wildcard = HostPattern::parse("*.example.com").unwrap();
+        assert!(wildcard.matches("a.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("aexample.com"));
+
+        let suffix = HostPattern::parse(".example.com").unwrap();
+        assert!(suffix.matches("example.com"));
+        assert!(suffix.matches("a.example.com"));
+        assert!(!suffix.matches("aexample.com"));
+
+        let regex = HostPattern::parse(r"~^api\d+\.example\.com$").unwrap();
+        assert!(regex.matches("API1.example.com"));
+        assert!(!regex.matches("api.example.com"));
+
+        let idn = HostPattern::parse("*.bücher.example").unwrap();
+        assert!(idn.matches("www.xn--bcher-kva.example"));
+
+        assert!(HostPattern::parse("").is_err());
+        assert!(HostPattern::parse("*").is_err());
+        assert!(HostPattern::parse("*.").is_err());
+        assert!(HostPattern::parse(".").is_err());
+        assert!(HostPattern::parse("a.*.com").is_err());
+        assert!(HostPattern::parse("~(").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use core::mem::size_of;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use crate::logic::Reasons;
+use crate::ngx_ext::shm::{LockedSlabPool, SharedData, SharedZone, SlabPool};
+use crate::util::{push_json_str, push_label_value};
+
+pub type StatsZone = SharedZone<Stats>;
+
+// how a validated request ended up
+pub enum Outcome {
+    Passed,
+    Rejected(Reasons),
+    // failed, but let through by report mode
+    Reported(Reasons),
+    // not validated at all, for an internal error
+    Error,
+}
+
+// lives in the shared memory zone, so only atomics and raw pointers into the zone.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Stats {
+    total: Counters,
+    // by the server selected by SNI and the listening port, newest first
+    servers: AtomicPtr<ServerStats>,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+struct Counters {
+    checked: AtomicU64,
+    passed: AtomicU64,
+    rejected: AtomicU64,
+    reported: AtomicU64,
+    errors: AtomicU64,
+    // indexed by the bit of the reason
+    rejected_reasons: [AtomicU64; u32::BITS as usize],
+    reported_reasons: [AtomicU64; u32::BITS as usize],
+}
+
+// longer names are truncated
+const SERVER_NAME_MAX: usize = 256;
+
+#[repr(C)]
+struct ServerStats {
+    next: AtomicPtr<ServerStats>,
+    port: u16,
+    name_len: usize,
+    name: [u8; SERVER_NAME_MAX],
+    counters: Counters,
+}
+
+impl SharedData for Stats {
+    fn create(pool: &LockedSlabPool) -> Option<*mut Self> {
+        pool.calloc(size_of::<Stats>())
+    }
+}
+
+impl Stats {
+    // a full zone only loses the breakdown by server
+    pub fn count(&self, pool: &SlabPool, server: &[u8], port: u16, outcome: &Outcome) {
+        self.total.count(outcome);
+        if let Some(server) = self.server(pool, server, port) {
+            server.counters.count(outcome);
+        }
+    }
+
+    fn server(&self, pool: &SlabPool, name: &[u8], port: u16) -> Option<&ServerStats> {
+        let name = &name[..name.len().min(SERVER_NAME_MAX)];
+        if let Some(server) = self.find(name, port) {
+            return Some(server);
+        }
+        pool.lock(|locked| {
+            // another worker may have added it in the meantime
+            if let Some(server) = self.find(name, port) {
+                return Some(server);
+            }
+            let p: *mut ServerStats = locked.calloc(size_of::<ServerStats>())?;
+            let server = unsafe { &mut *p };
+            server.port = port;
+            server.name[..name.len()].copy_from_slice(name);
+            server.name_len = name.len();
+            server
+                .next
+                .store(self.servers.load(Ordering::Acquire), Ordering::Relaxed);
+            self.servers.store(p, Ordering::Release);
+            Some(&*server)
+        })
+    }
+
+    fn find(&self, name: &[u8], port: u16) -> Option<&ServerStats> {
+        self.servers()
+            .find(|server| server.port == port && server.name() == name)
+    }
+
+    fn servers(&self) -> impl Iterator<Item = &ServerStats> {
+        let mut p = self.servers.load(Ordering::Acquire);
+        core::iter::from_fn(move || {
+            let server = unsafe { p.as_ref() }?;
+            p = server.next.load(Ordering::Acquire);
+            Some(server)
+        })
+    }
+
+    // {<counters>,"servers":[{"name":..,"port":..,<counters>},..]}
+    pub fn render_json(&self) -> String {
+        let mut out = String::from("{");
+        self.total.render_json(&mut out);
+        out.push_str(",\"servers\":[");
+        for (i, server) in self.servers().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"name\":");
+            push_json_str(&mut out, &String::from_utf8_lossy(server.name()));
+            out.push_str(&format!(",\"port\":{},", server.port));
+            server.counters.render_json(&mut out);
+            out.push('}');
+        }
+        out.push_str("]}\n");
+        out
+    }
+
+    // the text exposition format, labeled by server and port
+    pub fn render_prometheus(&self) -> String {
+        let servers: Vec<(String, &ServerStats)> = self
+            .servers()
+            .map(|server| {
+                let mut labels = String::from("server=\"");
+                push_label_value(&mut labels, &String::from_utf8_lossy(server.name()));
+                labels.push_str(&format!("\",port=\"{}\"", server.port));
+                (labels, server)
+            })
+            .collect();
+        let mut out = String::new();
+        for (name, help, counter) in METRICS {
+            push_metric_header(&mut out, name, help);
+            for (labels, server) in &servers {
+                let count = counter(&server.counters).load(Ordering::Relaxed);
+                out.push_str(&format!("strict_sni_{name}_total{{{labels}}} {count}\n"));
+            }
+        }
+        for (name, help, counters) in REASON_METRICS {
+            push_metric_header(&mut out, name, help);
+            for (labels, server) in &servers {
+                for (reason, count) in reason_counts(counters(&server.counters)) {
+                    out.push_str(&format!(
+                        "strict_sni_{name}_total{{{labels},reason=\"{reason}\"}} {count}\n"
+                    ));
+                }
+            }
+        }
+        out
+    }
+}
+
+type Metric = (&'static str, &'static str, fn(&Counters) -> &AtomicU64);
+const METRICS: [Metric; 5] = [
+    ("requests_checked", "Requests validated.", |c| &c.checked),
+    ("requests_passed", "Requests passed.", |c| &c.passed),
+    ("requests_rejected", "Requests rejected.", |c| &c.rejected),
+    (
+        "requests_reported",
+        "Requests failed, but let through by report mode.",
+        |c| &c.reported,
+    ),
+    (
+        "analysis_errors",
+        "Requests not validated for an internal error.",
+        |c| &c.errors,
+    ),
+];
+
+type ReasonMetric = (&'static str, &'static str, fn(&Counters) -> &[AtomicU64]);
+const REASON_METRICS: [ReasonMetric; 2] = [
+    (
+        "rejection_reasons",
+        "Reasons of the rejected requests.",
+        |c| &c.rejected_reasons,
+    ),
+    (
+        "report_reasons",
+        "Reasons of the requests failed in report mode.",
+        |c| &c.reported_reasons,
+    ),
+];
+
+fn push_metric_header(out: &mut String, name: &str, help: &str) {
+    out.push_str(&format!(
+        "# HELP strict_sni_{name}_total {help}\n# TYPE strict_sni_{name}_total counter\n"
+    ));
+}
+
+// (name, count) of every reason
+fn reason_counts(counters: &[AtomicU64]) -> impl Iterator<Item = (String, u64)> + '_ {
+    Reasons::all().iter_names().map(|(name, reason)| {
+        let count = counters[reason.bits().trailing_zeros() as usize].load(Ordering::Relaxed);
+        (name.to_ascii_lowercase(), count)
+    })
+}
+
+impl ServerStats {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+impl Counters {
+    fn count(&self, outcome: &Outcome) {
+        let (counter, reasons) = match outcome {
+            Outcome::Passed => (&self.passed, None),
+            Outcome::Rejected(reasons) => (&self.rejected, Some((&self.rejected_reasons, reasons))),
+            Outcome::Reported(reasons) => (&self.reported, Some((&self.reported_reasons, reasons))),
+            Outcome::Error => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some((counters, reasons)) = reasons {
+            for reason in reasons.iter() {
+                counters[reason.bits().trailing_zeros() as usize].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn render_json(&self, out: &mut String) {
+        out.push_str(&format!(
+            "\"checked\":{},\"passed\":{},\"rejected\":{},\"reported\":{},\"errors\":{}",
+            self.checked.load(Ordering::Relaxed),
+            self.passed.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            self.reported.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+        ));
+        for (key, counters) in [
+            ("rejected_reasons", &self.rejected_reasons),
+            ("reported_reasons", &self.reported_reasons),
+        ] {
+            out.push_str(&format!(",\"{}\":{{", key));
+            for (i, (reason, count)) in reason_counts(counters).enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&format!("\"{}\":{}", reason, count));
+            }
+            out.push('}');
+        }
+    }
+}
@@ -1,22 +1,334 @@
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use core::str;
+use core::sync::atomic::{AtomicI64, AtomicU32, Ordering};
 
 use fluent_uri::UriRef;
+use idna::{domain_to_ascii_cow, AsciiDenyList};
 
+// RFC 3986 authority without userinfo: host [ ":" port ]
+// where host = IP-literal / IPv4address / reg-name.
+// reg-name also takes non-ASCII (RFC 3987 ireg-name), which canonicalize_host maps to A-labels.
+#[derive(Debug)]
 pub struct ParseHostHeaderError;
 pub fn parse_host_header(host_header: &str) -> Result<(&str, Option<u16>), ParseHostHeaderError> {
-    Ok(match host_header.rsplit_once(':') {
-        Some((host, port_str)) => {
-            if port_str.is_empty() {
-                (host, None)
-            } else {
-                (
-                    host,
-                    Some(port_str.parse().map_err(|_| ParseHostHeaderError)?),
-                )
+    let (host, port_str) = if host_header.starts_with('[') {
+        let end = host_header.find(']').ok_or(ParseHostHeaderError)?;
+        let (host, rest) = host_header.split_at(end + 1);
+        if !is_ip_literal(host) {
+            return Err(ParseHostHeaderError);
+        }
+        if rest.is_empty() {
+            (host, None)
+        } else {
+            (
+                host,
+                Some(rest.strip_prefix(':').ok_or(ParseHostHeaderError)?),
+            )
+        }
+    } else {
+        let (host, port_str) = match host_header.split_once(':') {
+            Some((host, port_str)) => (host, Some(port_str)),
+            None => (host_header, None),
+        };
+        // IPv4address is a special case of reg-name
+        if !is_reg_name(host) {
+            return Err(ParseHostHeaderError);
+        }
+        (host, port_str)
+    };
+    let port = match port_str {
+        None | Some("") => None,
+        Some(port_str) => {
+            if !port_str.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseHostHeaderError);
+            }
+            Some(port_str.parse().map_err(|_| ParseHostHeaderError)?)
+        }
+    };
+    Ok((host, port))
+}
+
+fn is_ip_literal(host: &str) -> bool {
+    if let Some(inner) = host.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        if let Some(future) = inner.strip_prefix(['v', 'V']) {
+            // IPvFuture = "v" 1*HEXDIG "." 1*( unreserved / sub-delims / ":" )
+            if let Some((ver, addr)) = future.split_once('.') {
+                return !ver.is_empty()
+                    && ver.bytes().all(|b| b.is_ascii_hexdigit())
+                    && !addr.is_empty()
+                    && addr
+                        .bytes()
+                        .all(|b| is_unreserved(b) || is_sub_delim(b) || b == b':');
             }
+            return false;
         }
-        None => (host_header, None),
-    })
+        return inner.parse::<Ipv6Addr>().is_ok();
+    }
+    false
+}
+
+fn is_reg_name(host: &str) -> bool {
+    let bytes = host.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if b == b'%' {
+            if !(i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit())
+            {
+                return false;
+            }
+            i += 3;
+            continue;
+        }
+        if !(is_unreserved(b) || is_sub_delim(b) || !b.is_ascii()) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+fn is_sub_delim(b: u8) -> bool {
+    matches!(
+        b,
+        b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'='
+    )
+}
+
+// the address of an IP host (IPv4address or bracketed IPv6 IP-literal).
+pub fn host_ip(host: &str) -> Option<IpAddr> {
+    if let Some(inner) = host.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        inner.parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+    } else {
+        host.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+    }
+}
+
+// RFC 9110 7.2: Host = uri-host [ ":" port ]
+// i.e. no userinfo, no path, and nothing outside of the (ASCII) authority syntax.
+pub fn is_valid_host_header(host_header: &str) -> bool {
+    host_header.is_ascii() && parse_host_header(host_header).is_ok_and(|(host, _)| !host.is_empty())
+}
+
+// Canonical form of a host name (SNI, Host, the request target, ...):
+// percent-decoded, without the trailing dot, and UTS-46 mapped to lowercase A-labels.
+// IPv6 literals are reformatted in the RFC 5952 text form.
+#[derive(Debug)]
+pub struct CanonicalizeHostError;
+pub fn canonicalize_host(host: &str) -> Result<String, CanonicalizeHostError> {
+    if host.starts_with('[') {
+        return match host_ip(host) {
+            Some(ip) => Ok(format!("[{}]", ip)),
+            None => Ok(host.to_ascii_lowercase()),
+        };
+    }
+    let decoded = percent_decode(host)?;
+    let name = decoded.strip_suffix('.').unwrap_or(&decoded);
+    if name.is_empty() {
+        return Err(CanonicalizeHostError);
+    }
+    let ascii = domain_to_ascii_cow(name.as_bytes(), AsciiDenyList::URL)
+        .map_err(|_| CanonicalizeHostError)?;
+    // an empty label ("a..b", or a dot left after stripping) is never a valid name
+    if ascii.split('.').any(str::is_empty) {
+        return Err(CanonicalizeHostError);
+    }
+    Ok(ascii.into_owned())
+}
+
+fn percent_decode(s: &str) -> Result<String, CanonicalizeHostError> {
+    if !s.contains('%') {
+        return Ok(s.to_string());
+    }
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [
+                iter.next().ok_or(CanonicalizeHostError)?,
+                iter.next().ok_or(CanonicalizeHostError)?,
+            ];
+            let hex = str::from_utf8(&hex).map_err(|_| CanonicalizeHostError)?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| CanonicalizeHostError)?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| CanonicalizeHostError)
+}
+
+// at most `limit` permits a second, and the count of the refused ones since the last permit.
+// the second is given by the caller, which has it cached anyway.
+pub struct RateLimiter {
+    limit: u32,
+    second: AtomicI64,
+    count: AtomicU32,
+    refused: AtomicU32,
+}
+impl RateLimiter {
+    pub const fn new(limit: u32) -> Self {
+        RateLimiter {
+            limit,
+            second: AtomicI64::new(i64::MIN),
+            count: AtomicU32::new(0),
+            refused: AtomicU32::new(0),
+        }
+    }
+    pub fn permit(&self, second: i64) -> Option<u32> {
+        if self.second.swap(second, Ordering::Relaxed) != second {
+            self.count.store(0, Ordering::Relaxed);
+        }
+        if self.count.fetch_add(1, Ordering::Relaxed) >= self.limit {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(self.refused.swap(0, Ordering::Relaxed))
+    }
+}
+
+// a JSON string literal, quoted and escaped.
+pub fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// the host= parameters of an RFC 7239 Forwarded header, unquoted, in the order given
+#[derive(Debug)]
+pub struct ParseForwardedError;
+pub fn parse_forwarded_hosts(forwarded: &str) -> Result<Vec<String>, ParseForwardedError> {
+    let mut hosts = Vec::new();
+    let mut rest = forwarded;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',', ';']);
+        if rest.is_empty() {
+            return Ok(hosts);
+        }
+        let (key, value) = rest.split_once('=').ok_or(ParseForwardedError)?;
+        let key = key.trim();
+        if key.is_empty() || !key.bytes().all(is_tchar) {
+            return Err(ParseForwardedError);
+        }
+        let value = value.trim_start_matches([' ', '\t']);
+        let (value, next) = if let Some(quoted) = value.strip_prefix('"') {
+            // quoted-string with quoted-pairs
+            let mut unquoted = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next().ok_or(ParseForwardedError)? {
+                    (i, '"') => break i,
+                    (_, '\\') => unquoted.push(chars.next().ok_or(ParseForwardedError)?.1),
+                    (_, c) => unquoted.push(c),
+                }
+            };
+            (unquoted, &quoted[end + 1..])
+        } else {
+            let end = value.find([',', ';']).unwrap_or(value.len());
+            let token = value[..end].trim_end_matches([' ', '\t']);
+            // a token by the RFC, but an unquoted host:port is common enough to take
+            if token.is_empty()
+                || !token
+                    .bytes()
+                    .all(|b| is_tchar(b) || b == b':' || b == b'[' || b == b']')
+            {
+                return Err(ParseForwardedError);
+            }
+            (token.to_string(), &value[end..])
+        };
+        let next = next.trim_start_matches([' ', '\t']);
+        if !(next.is_empty() || next.starts_with([',', ';'])) {
+            return Err(ParseForwardedError);
+        }
+        if key.eq_ignore_ascii_case("host") {
+            hosts.push(value);
+        }
+        rest = next;
+    }
+}
+
+// RFC 9110 5.6.2
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// an address block, e.g. 192.0.2.0/24 or 2001:db8::/32; a bare address is a block of its own.
+// IPv4-mapped IPv6 addresses are matched as IPv4.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug)]
+pub struct ParseCidrError;
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Self, ParseCidrError> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| ParseCidrError)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            // no sign, no leading zeros
+            Some(p) if p.starts_with('0') && p.len() > 1 => return Err(ParseCidrError),
+            Some(p) if p.bytes().all(|b| b.is_ascii_digit()) => {
+                p.parse().map_err(|_| ParseCidrError)?
+            }
+            Some(_) => return Err(ParseCidrError),
+            None => max,
+        };
+        if prefix > max {
+            return Err(ParseCidrError);
+        }
+        // ::ffff:192.0.2.0/120 is 192.0.2.0/24
+        let (addr, prefix) = match addr {
+            IpAddr::V6(v6) if prefix >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => (IpAddr::V4(v4), prefix - 96),
+                None => (addr, prefix),
+            },
+            _ => (addr, prefix),
+        };
+        Ok(Cidr { addr, prefix })
+    }
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(block), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(block) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(block), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(block) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// escaped for a label value of the Prometheus text format, without the quotes
+pub fn push_label_value(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
 }
 
 // pub enum URI<'a> {
@@ -39,8 +351,219 @@ pub fn parse_request_line(
     Err(ParseRequestLineError)
 }
 
+// RFC 9112 3.2.3 authority-form of a CONNECT target: host ":" port, with the port required
+#[derive(Debug)]
+pub struct ParseAuthorityFormError;
+pub fn parse_authority_form(target: &str) -> Result<(&str, u16), ParseAuthorityFormError> {
+    match parse_host_header(target) {
+        Ok((host, Some(port))) if !host.is_empty() => Ok((host, port)),
+        _ => Err(ParseAuthorityFormError),
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{
+        canonicalize_host, host_ip, is_valid_host_header, parse_authority_form,
+        parse_forwarded_hosts, parse_host_header, push_json_str, push_label_value, Cidr,
+        RateLimiter,
+    };
+
+    #[test]
+    fn parse_host_header_test() {
+        let parse = |s| parse_host_header(s).ok();
+        assert_eq!(parse("example.com"), Some(("example.com", None)));
+        assert_eq!(parse("example.com:"), Some(("example.com", None)));
+        assert_eq!(parse("example.com:8443"), Some(("example.com", Some(8443))));
+        assert_eq!(parse("127.0.0.1:80"), Some(("127.0.0.1", Some(80))));
+        assert_eq!(parse("[::1]"), Some(("[::1]", None)));
+        assert_eq!(parse("[::1]:443"), Some(("[::1]", Some(443))));
+        assert_eq!(parse("[2001:db8::1]"), Some(("[2001:db8::1]", None)));
+        assert_eq!(parse("[v1.fe80::a+en1]"), Some(("[v1.fe80::a+en1]", None)));
+        assert_eq!(
+            parse("b%C3%BCcher.example"),
+            Some(("b%C3%BCcher.example", None))
+        );
+        assert_eq!(parse("bücher.example"), Some(("bücher.example", None)));
+        assert_eq!(parse("::1"), None);
+        assert_eq!(parse("[::1"), None);
+        assert_eq!(parse("[::1]443"), None);
+        assert_eq!(parse("[::g]"), None);
+        assert_eq!(parse("[v1.]"), None);
+        assert_eq!(parse("example.com:+1"), None);
+        assert_eq!(parse("example.com:65536"), None);
+        assert_eq!(parse("example.com:1:2"), None);
+        assert_eq!(parse("ex%2"), None);
+        assert_eq!(parse("u@example.com"), None);
+    }
+
+    #[test]
+    fn parse_authority_form_test() {
+        let parse = |s| parse_authority_form(s).ok();
+        assert_eq!(parse("example.com:443"), Some(("example.com", 443)));
+        assert_eq!(parse("127.0.0.1:8443"), Some(("127.0.0.1", 8443)));
+        assert_eq!(parse("[::1]:443"), Some(("[::1]", 443)));
+        assert_eq!(parse("example.com"), None);
+        assert_eq!(parse("example.com:"), None);
+        assert_eq!(parse("[::1]"), None);
+        assert_eq!(parse(":443"), None);
+        assert_eq!(parse("/"), None);
+        assert_eq!(parse("https://example.com:443/"), None);
+    }
+
+    #[test]
+    fn host_ip_test() {
+        assert_eq!(host_ip("127.0.0.1"), Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(host_ip("[::1]"), Some(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert_eq!(host_ip("::1"), None);
+        assert_eq!(host_ip("127.0.0.1.example"), None);
+        assert_eq!(host_ip("localhost"), None);
+    }
+
+    #[test]
+    fn canonicalize_host_test() {
+        let canon = |s| canonicalize_host(s).ok();
+        assert_eq!(canon("Example.COM").as_deref(), Some("example.com"));
+        assert_eq!(canon("example.com.").as_deref(), Some("example.com"));
+        assert_eq!(
+            canon("bücher.example").as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+        assert_eq!(
+            canon("BÜCHER.example").as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+        assert_eq!(
+            canon("xn--bcher-kva.example").as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+        assert_eq!(
+            canon("b%C3%BCcher.example").as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+        assert_eq!(canon("ex%61mple.com").as_deref(), Some("example.com"));
+        assert_eq!(canon("[::1]").as_deref(), Some("[::1]"));
+        assert_eq!(canon("[0:0::0001]").as_deref(), Some("[::1]"));
+        assert_eq!(canon(""), None);
+        assert_eq!(canon("."), None);
+        assert_eq!(canon("example.com.."), None);
+        assert_eq!(canon("a..b"), None);
+        assert_eq!(canon("ex%2"), None);
+        assert_eq!(canon("ex%ZZ"), None);
+        assert_eq!(canon("ex%FF"), None);
+        assert_eq!(canon("exa mple.com"), None);
+        assert_eq!(canon("ex%2Fample.com"), None);
+        assert_eq!(canon("xn--a.example"), None);
+    }
+
+    #[test]
+    fn host_header_validity_test() {
+        assert!(is_valid_host_header("example.com"));
+        assert!(is_valid_host_header("example.com:8443"));
+        assert!(is_valid_host_header("127.0.0.1:80"));
+        assert!(is_valid_host_header("[::1]:443"));
+        assert!(!is_valid_host_header(""));
+        assert!(!is_valid_host_header("user@example.com"));
+        assert!(!is_valid_host_header("example.com/path"));
+        assert!(!is_valid_host_header("example.com?q"));
+        assert!(!is_valid_host_header("exa mple.com"));
+        assert!(!is_valid_host_header("example.com:99999"));
+        assert!(!is_valid_host_header("bücher.example"));
+    }
+
+    #[test]
+    fn rate_limiter_test() {
+        let limiter = RateLimiter::new(2);
+        assert_eq!(limiter.permit(10), Some(0));
+        assert_eq!(limiter.permit(10), Some(0));
+        assert_eq!(limiter.permit(10), None);
+        assert_eq!(limiter.permit(10), None);
+        assert_eq!(limiter.permit(11), Some(2));
+        assert_eq!(limiter.permit(11), Some(0));
+        assert_eq!(limiter.permit(11), None);
+        assert_eq!(limiter.permit(12), Some(1));
+    }
+
+    #[test]
+    fn parse_forwarded_hosts_test() {
+        let hosts = |s| parse_forwarded_hosts(s).ok();
+        assert_eq!(
+            hosts("host=example.com"),
+            Some(vec!["example.com".to_string()])
+        );
+        assert_eq!(
+            hosts(r#"for=192.0.2.60;proto=https;Host="example.com:8443""#),
+            Some(vec!["example.com:8443".to_string()])
+        );
+        assert_eq!(
+            hosts(r#"host=a.example, for="[2001:db8::1]";host="b\.example""#),
+            Some(vec!["a.example".to_string(), "b.example".to_string()])
+        );
+        assert_eq!(hosts("host=[::1]:443"), Some(vec!["[::1]:443".to_string()]));
+        assert_eq!(hosts("for=192.0.2.60"), Some(vec![]));
+        assert_eq!(hosts(""), Some(vec![]));
+        assert_eq!(hosts("host"), None);
+        assert_eq!(hosts("host=\"a.example"), None);
+        assert_eq!(hosts("host=\"a.example\"x"), None);
+        assert_eq!(hosts("host=a b"), None);
+        assert_eq!(hosts("ho st=a"), None);
+    }
+
+    #[test]
+    fn cidr_test() {
+        let contains = |cidr, addr: &str| {
+            Cidr::parse(cidr)
+                .ok()
+                .unwrap()
+                .contains(addr.parse().unwrap())
+        };
+        assert!(contains("192.0.2.0/24", "192.0.2.1"));
+        assert!(contains("192.0.2.0/24", "::ffff:192.0.2.255"));
+        assert!(!contains("192.0.2.0/24", "192.0.3.1"));
+        assert!(contains("192.0.2.1", "192.0.2.1"));
+        assert!(!contains("192.0.2.1", "192.0.2.2"));
+        assert!(contains("0.0.0.0/0", "198.51.100.1"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(contains("2001:db8::/32", "2001:db8:1::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("::/0", "::1"));
+        assert!(contains("::ffff:192.0.2.0/120", "192.0.2.7"));
+        assert!(Cidr::parse("192.0.2.0/33").is_err());
+        assert!(Cidr::parse("2001:db8::/129").is_err());
+        assert!(Cidr::parse("192.0.2.0/+8").is_err());
+        assert!(Cidr::parse("192.0.2.0/08").is_err());
+        assert!(Cidr::parse("192.0.2.0/").is_err());
+        assert!(Cidr::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn push_json_str_test() {
+        let json = |s| {
+            let mut out = String::new();
+            push_json_str(&mut out, s);
+            out
+        };
+        assert_eq!(json("example.com"), r#""example.com""#);
+        assert_eq!(json(r#"~^a"b\d$"#), r#""~^a\"b\\d$""#);
+        assert_eq!(json("a\tb"), r#""a\u0009b""#);
+        assert_eq!(json(""), r#""""#);
+    }
+
+    #[test]
+    fn push_label_value_test() {
+        let label = |s| {
+            let mut out = String::new();
+            push_label_value(&mut out, s);
+            out
+        };
+        assert_eq!(label("example.com"), "example.com");
+        assert_eq!(label(r#"~^a"b\d$"#), r#"~^a\"b\\d$"#);
+        assert_eq!(label("a\nb"), r"a\nb");
+        assert_eq!(label(""), "");
+    }
+
     #[test]
     fn split_test() {
         let rl = "GET     /efnepfnap     x";
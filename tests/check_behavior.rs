@@ -6,6 +6,8 @@ mod tests {
     const TEST_NGINX_CONF: &str = "tests/nginx.conf";
     const TEST_NGINX_PEM: &str = "tests/nginx.pem";
     const TEST_NGINX_KEY: &str = "tests/nginx.key";
+    const TEST_NGINX_SAN_PEM: &str = "tests/nginx_san.pem";
+    const TEST_NGINX_SAN_KEY: &str = "tests/nginx_san.key";
 
     const TEST_CURL_TUPLE: [(&str, Option<&str>, u32); 32] = [
         // ssl root
@@ -50,6 +52,433 @@ mod tests {
         ("http://localhost:8080/dull", Some("localhost:8888"), 301),
     ];
 
+    // Host "" means no Host header at all (sent as HTTP/1.0).
+    // 127.0.0.1 urls make curl omit SNI.
+    const TEST_CURL_RIGOR_TUPLE: [(&str, Option<&str>, u32); 8] = [
+        // ssl normal rigor
+        ("https://localhost:4433", Some(""), 200),
+        ("https://127.0.0.1:4433", Some("localhost:4433"), 200),
+        ("https://127.0.0.1:4433", Some("localguest:4433"), 200),
+        ("https://127.0.0.1:4433", Some("localhost:4422"), 421),
+        // ssl strict rigor
+        ("https://localhost:4434", None, 200),
+        ("https://localhost:4434", Some(""), 421),
+        ("https://127.0.0.1:4434", Some("localhost:4434"), 421),
+        ("https://localhost:4434", Some("localguest:4434"), 421),
+    ];
+
+    const TEST_CURL_RFC_TUPLE: [(&str, Option<&str>, u32); 4] = [
+        ("https://localhost:4433/rfc", Some("localhost:4433"), 301),
+        ("https://localhost:4433/rfc", Some("localguest:4433"), 421),
+        ("https://localhost:4433/rfc", Some("u@localhost:4433"), 400),
+        ("https://localhost:4433/sub", Some("u@localhost:4433"), 421),
+    ];
+
+    // the certificate on 4435 covers localhost and alias.localhost
+    const TEST_CURL_CERT_TUPLE: [(&str, Option<&str>, u32); 6] = [
+        ("https://localhost:4435", None, 200),
+        ("https://localhost:4435", Some("alias.localhost:4435"), 200),
+        ("https://localhost:4435", Some("localguest:4435"), 421),
+        ("https://127.0.0.1:4435", Some("alias.localhost:4435"), 200),
+        ("https://localhost:4435/sni", Some("localhost:4435"), 301),
+        (
+            "https://localhost:4435/sni",
+            Some("alias.localhost:4435"),
+            421,
+        ),
+    ];
+
+    // 4436 has two servers: "localhost alias.localhost" and "other.localhost"
+    const TEST_CURL_SERVER_TUPLE: [(&str, Option<&str>, u32); 6] = [
+        ("https://localhost:4436", Some("localhost:4436"), 200),
+        ("https://localhost:4436", Some("alias.localhost:4436"), 200),
+        ("https://alias.localhost:4436", Some("localhost:4436"), 200),
+        ("https://localhost:4436", Some("other.localhost:4436"), 421),
+        ("https://other.localhost:4436", None, 200),
+        ("https://other.localhost:4436", Some("localhost:4436"), 421),
+    ];
+
+    const TEST_CURL_ALIAS_TUPLE: [(&str, Option<&str>, u32); 5] = [
+        (
+            "https://localhost:4433/alias",
+            Some("api.localhost:4433"),
+            301,
+        ),
+        (
+            "https://localhost:4433/alias",
+            Some("api1.example:4433"),
+            301,
+        ),
+        (
+            "https://localhost:4433/alias",
+            Some("api.example:4433"),
+            421,
+        ),
+        ("https://localhost:4433/alias", Some("localguest:4433"), 421),
+        (
+            "https://localhost:4433/sub",
+            Some("api.localhost:4433"),
+            421,
+        ),
+    ];
+
+    const TEST_CURL_CANONICAL_TUPLE: [(&str, Option<&str>, u32); 4] = [
+        ("https://localhost:4433", Some("LocalHost.:4433"), 200),
+        ("https://localhost:4433", Some("local%68ost:4433"), 200),
+        ("https://localhost:4433", Some("local%ZZost:4433"), 421),
+        (
+            "https://localhost:4435",
+            Some("alias%2Elocalhost:4435"),
+            200,
+        ),
+    ];
+
+    const TEST_CURL_IP_TUPLE: [(&str, Option<&str>, u32); 5] = [
+        ("https://127.0.0.1:4433", Some("127.0.0.1:4433"), 200),
+        ("https://127.0.0.1:4433", Some("127.0.0.2:4433"), 421),
+        ("https://127.0.0.1:4433", Some("[::1]:4433"), 421),
+        ("http://127.0.0.1:8080", Some("[::1]:8080"), 200),
+        ("http://127.0.0.1:8080", Some("[::1]"), 421),
+    ];
+
+    // 444 stands for the connection closed without a response
+    const TEST_CURL_REJECT_TUPLE: [(&str, Option<&str>, u32); 5] = [
+        ("https://localhost:4433/reject", Some("localhost:4433"), 301),
+        (
+            "https://localhost:4433/reject",
+            Some("localguest:4433"),
+            403,
+        ),
+        ("https://localhost:4433/reject", Some("localhost:4434"), 400),
+        ("https://localhost:4433/drop", Some("localguest:4433"), 444),
+        ("https://localhost:4433/drop", Some("localhost:4433"), 301),
+    ];
+
+    // without SNI there is nowhere to redirect to
+    const TEST_CURL_REDIRECT_TUPLE: [(&str, Option<&str>, u32); 5] = [
+        ("https://localhost:4433/redirect/", Some("other:4433"), 307),
+        (
+            "https://localhost:4433/redirect/",
+            Some("localhost:80"),
+            307,
+        ),
+        (
+            "https://localhost:4433/redirect308/",
+            Some("other:4433"),
+            308,
+        ),
+        ("https://127.0.0.1:4433/redirect/", Some("other:4433"), 421),
+        (
+            "https://localhost:4433/redirect/",
+            Some("localhost:4433"),
+            200,
+        ),
+    ];
+
+    // failing, but let through
+    const TEST_CURL_REPORT_TUPLE: [(&str, Option<&str>, u32); 3] = [
+        ("https://localhost:4433/report/", Some("other:4433"), 200),
+        ("https://localhost:4433/report/", Some("other:80"), 200),
+        ("https://localhost:4433/report/", Some("[::1]"), 200),
+    ];
+
+    // 204 pass, 403 fail, 410 fail by port
+    const TEST_CURL_VARIABLE_TUPLE: [(&str, Option<&str>, u32); 3] = [
+        ("https://localhost:4433/vars", Some("localhost:4433"), 204),
+        ("https://localhost:4433/vars", Some("other:4433"), 403),
+        ("https://localhost:4433/vars", Some("localhost:4434"), 410),
+    ];
+
+    // 204 exempt, 403 for a reason given all the same
+    const TEST_CURL_EXEMPT_TUPLE: [(&str, Option<&str>, u32); 4] = [
+        (
+            "https://localhost:4433/exempt/",
+            Some("localhost:4433"),
+            200,
+        ),
+        ("https://localhost:4433/exempt/", Some("other:4433"), 204),
+        (
+            "https://localhost:4433/exempt_other/",
+            Some("localhost:4433"),
+            200,
+        ),
+        (
+            "https://localhost:4433/exempt_other/",
+            Some("other:4433"),
+            421,
+        ),
+    ];
+
+    const TEST_CURL_SNI_SOURCE_TUPLE: [(&str, Option<&str>, u32); 4] = [
+        (
+            "http://localhost:8080/offload/",
+            Some("localhost:8080"),
+            200,
+        ),
+        (
+            "http://localhost:8080/offload/",
+            Some("localguest:8080"),
+            421,
+        ),
+        (
+            "http://localhost:8080/offload_untrusted/",
+            Some("localhost:8080"),
+            200,
+        ),
+        (
+            "http://localhost:8080/offload_untrusted/",
+            Some("localguest:8080"),
+            200,
+        ),
+    ];
+
+    // with an extra header instead of Host; 200 stripped, 204 passed on
+    const TEST_CURL_HEADER_TUPLE: [(&str, &str, u32); 11] = [
+        (
+            "https://localhost:4433/forwarded/",
+            "X-Forwarded-Host: localhost:4433",
+            200,
+        ),
+        (
+            "https://localhost:4433/forwarded/",
+            "X-Forwarded-Host: other:4433",
+            421,
+        ),
+        (
+            "https://localhost:4433/forwarded/",
+            "Forwarded: for=127.0.0.1;host=\"localhost:4433\"",
+            200,
+        ),
+        (
+            "https://localhost:4433/forwarded/",
+            "Forwarded: host=other",
+            421,
+        ),
+        ("https://localhost:4433/forwarded/", "Forwarded: host", 421),
+        (
+            "https://localhost:4433/forwarded_strip",
+            "X-Forwarded-Host: localhost:4433",
+            204,
+        ),
+        (
+            "https://localhost:4433/forwarded_strip",
+            "X-Forwarded-Host: other:4433",
+            200,
+        ),
+        (
+            "https://localhost:4433/forwarded_strip",
+            "Forwarded: host=\"localhost:4433\"",
+            204,
+        ),
+        (
+            "https://localhost:4433/forwarded_strip",
+            "Forwarded: host=other",
+            200,
+        ),
+        // never redirected, unlike a failed host check
+        (
+            "https://localhost:4433/forwarded_redirect/",
+            "X-Forwarded-Host: other:4433",
+            421,
+        ),
+        (
+            "https://localhost:4433/forwarded_redirect/",
+            "X-Forwarded-Host: localhost:4433",
+            200,
+        ),
+    ];
+
+    // 4441 is published on 443, and 4442 on 8443
+    const TEST_CURL_PORT_MAP_TUPLE: [(&str, Option<&str>, u32); 6] = [
+        ("https://localhost:4441", Some("localhost"), 200),
+        ("https://localhost:4441", Some("localhost:443"), 200),
+        ("https://localhost:4441", Some("localhost:4441"), 421),
+        ("https://localhost:4442", Some("localhost:8443"), 200),
+        ("https://localhost:4442", Some("localhost:4442"), 421),
+        ("https://localhost:4442", Some("localhost"), 421),
+    ];
+
+    // in turn on one connection, of which the first authority is localhost:4438
+    // over one HTTP/2 connection, where curl sends Host as :authority
+    const TEST_CURL_COALESCING_TUPLE: [(&str, Option<&str>, u32); 6] = [
+        ("https://localhost:4438", None, 200),
+        ("https://localhost:4438", Some("alias.localhost:4438"), 421),
+        // another name of the certificate, as a coalescing browser sends
+        (
+            "https://localhost:4438/allow/",
+            Some("alias.localhost:4438"),
+            200,
+        ),
+        (
+            "https://localhost:4438/same_cert/",
+            Some("alias.localhost:4438"),
+            200,
+        ),
+        (
+            "https://localhost:4438/same_cert/",
+            Some("localguest:4438"),
+            421,
+        ),
+        ("https://localhost:4438", Some("localhost:4438"), 200),
+    ];
+
+    // nghttp sends a Host header beside :authority, which curl never does;
+    // it is not installed everywhere, so it is tested only if the variable is set.
+    const TEST_NGHTTP_ENV: &str = "STRICT_SNI_TEST_NGHTTP";
+    const TEST_NGHTTP_TUPLE: [(&str, &[&str], u32); 4] = [
+        ("https://localhost:4437", &["host: localhost:4437"], 200),
+        ("https://localhost:4437", &["host: localguest:4437"], 421),
+        ("https://localhost:4437", &["host: localhost:4422"], 421),
+        (
+            "https://localhost:4437",
+            &[":authority: localguest:4437", "host: localhost:4437"],
+            421,
+        ),
+    ];
+
+    // curl sends Host as :authority on HTTP/2
+    const TEST_CURL_HTTP2_TUPLE: [(&str, Option<&str>, u32); 4] = [
+        ("https://localhost:4437", None, 200),
+        ("https://localhost:4437", Some("localhost:4437"), 200),
+        ("https://localhost:4437", Some("localguest:4437"), 421),
+        ("https://localhost:4437", Some("localhost:4422"), 421),
+    ];
+
+    // (address, SNI, raw request) sent as is, for request targets curl never sends
+    const TEST_RAW_TUPLE: [(&str, &str, &str, u32); 6] = [
+        (
+            "127.0.0.1:4433",
+            "localhost",
+            "GET https://localhost:4433/ HTTP/1.1\r\nHost: localhost:4433\r\n",
+            200,
+        ),
+        (
+            "127.0.0.1:4433",
+            "localhost",
+            "GET https://localguest:4433/ HTTP/1.1\r\nHost: localhost:4433\r\n",
+            421,
+        ),
+        (
+            "127.0.0.1:4433",
+            "localhost",
+            "GET https://localhost:4433/ HTTP/1.0\r\n",
+            200,
+        ),
+        (
+            "127.0.0.1:4433",
+            "localhost",
+            "GET https://localguest:4433/ HTTP/1.0\r\n",
+            421,
+        ),
+        (
+            "127.0.0.1:4433",
+            "localhost",
+            "GET https://127.0.0.1:4433/ HTTP/1.0\r\n",
+            200,
+        ),
+        // CONNECT policy: only that it leaves the other methods alone, since nginx answers
+        // CONNECT over HTTP/1.x by itself (the policy is tested by validate_connect_test)
+        (
+            "127.0.0.1:4433",
+            "localhost",
+            "GET /connect HTTP/1.1\r\nHost: localhost:4433\r\n",
+            301,
+        ),
+    ];
+
+    const TEST_CURL_STATUS_TUPLE: [(&str, Option<&str>, u32); 3] = [
+        ("http://localhost:8080/status", None, 200),
+        ("http://localhost:8080/metrics", None, 200),
+        ("http://localhost:8080/bans", None, 200),
+    ];
+
+    // HTTP/3 needs nginx with the http_v3_module and libcurl with HTTP/3,
+    // so it is tested only if the variable is set.
+    const TEST_QUIC_ENV: &str = "STRICT_SNI_TEST_QUIC";
+    const TEST_NGINX_QUIC_CONF: &str = r#"
+server {
+    listen       127.0.0.1:4439 ssl;
+    listen       127.0.0.1:4440 quic;
+    server_name  localhost;
+
+    ssl_certificate nginx.pem;
+    ssl_certificate_key nginx.key;
+
+    add_header Alt-Svc 'h3=":4440"';
+
+    location / {
+        if ($server_protocol != "HTTP/3.0") {
+            return 505;
+        }
+        root   html;
+        index  index.html index.htm;
+    }
+}
+"#;
+
+    // over QUIC on 4440, as advertised by the TCP origin on 4439
+    const TEST_CURL_QUIC_TUPLE: [(&str, Option<&str>, u32); 6] = [
+        ("https://localhost:4440", None, 200),
+        ("https://localhost:4440", Some("localhost:4440"), 200),
+        ("https://localhost:4440", Some("localhost:4439"), 200),
+        ("https://localhost:4440", Some("localhost:4422"), 421),
+        // nginx listens on it over TCP, but for another server
+        ("https://localhost:4440", Some("localhost:4433"), 421),
+        ("https://localhost:4440", Some("localguest:4440"), 421),
+    ];
+
+    // strict_sni_proxy_protocol needs nginx 1.23.2 or later (see build.rs)
+    const TEST_NGINX_PROXY_CONF: &str = r#"
+server {
+    listen       127.0.0.1:4443 proxy_protocol;
+    server_name  localhost;
+
+    strict_sni_proxy_protocol on;
+
+    location / {
+        root   html;
+        index  index.html index.htm;
+    }
+}
+"#;
+
+    // (PP2_TYPE_AUTHORITY, destination port, PP2_CLIENT_SSL, Host) in PROXY protocol v2 to 4443
+    const TEST_PROXY_TUPLE: [(Option<&str>, u16, bool, &str, u32); 8] = [
+        (Some("localhost"), 443, true, "localhost", 200),
+        (Some("localhost"), 443, true, "localhost:443", 200),
+        (Some("localhost"), 443, true, "localguest", 421),
+        // without the authority TLV the host check fails closed
+        (None, 443, true, "localhost", 421),
+        // the port is the destination of the header, not the one nginx listens on
+        (Some("localhost"), 443, true, "localhost:4443", 421),
+        (Some("localhost"), 8443, true, "localhost:8443", 200),
+        // a client without TLS means port 80 for a Host without a port
+        (Some("localhost"), 443, false, "localhost", 421),
+        (Some("localhost"), 80, false, "localhost", 200),
+    ];
+
+    // never hit by the tests themselves, then lowered for the ban test
+    const TEST_NGINX_BAN_CONF: &str =
+        "strict_sni_ban threshold=100000 window=1m duration=1m zone=strict_sni_ban:1m;";
+    const TEST_NGINX_LOW_BAN_CONF: &str =
+        "strict_sni_ban threshold=2 window=1m duration=1m zone=strict_sni_ban:1m;";
+
+    // (Host) to 4444 from the client the PROXY protocol header of proxy_test gives to realip,
+    // so the rest of the tests is not banned: two rejections ban it,
+    // and even a valid request is refused then
+    const TEST_BAN_ADDR: &str = "192.0.2.1";
+    const TEST_PROXY_BAN_TUPLE: [(&str, u32); 4] = [
+        ("localhost:4444", 200),
+        ("localhost:4422", 421),
+        ("localhost:4422", 421),
+        ("localhost:4444", 403),
+    ];
+
+    const TEST_CURL_RESOLVE: [&str; 2] = [
+        "alias.localhost:4436:127.0.0.1",
+        "other.localhost:4436:127.0.0.1",
+    ];
+
     #[test]
     fn test() {
         // get nginx controller
@@ -60,6 +489,8 @@ mod tests {
         let test_config_path = current_dir.join(TEST_NGINX_CONF);
         let test_pem_path = current_dir.join(TEST_NGINX_PEM);
         let test_key_path = current_dir.join(TEST_NGINX_KEY);
+        let test_san_pem_path = current_dir.join(TEST_NGINX_SAN_PEM);
+        let test_san_key_path = current_dir.join(TEST_NGINX_SAN_KEY);
         let module_basename = format!(
             "{}{}{}",
             DLL_PREFIX,
@@ -107,6 +538,12 @@ mod tests {
         nginx
             .copy_config(&test_key_path, "localhost.key")
             .expect(format!("Unable to load KEY file: {}", test_key_path.display()).as_str());
+        nginx
+            .copy_config(&test_san_pem_path, "nginx_san.pem")
+            .expect(format!("Unable to load PEM file: {}", test_san_pem_path.display()).as_str());
+        nginx
+            .copy_config(&test_san_key_path, "nginx_san.key")
+            .expect(format!("Unable to load KEY file: {}", test_san_key_path.display()).as_str());
         nginx
             .copy_module(&module_path_from, &module_path_rel_to_str)
             .expect(
@@ -122,6 +559,20 @@ mod tests {
                 format!("load_module {};", &module_path_rel_to_str).as_str(),
             )
             .expect(format!("Unable to create config file").as_str());
+        let quic = std::env::var_os(TEST_QUIC_ENV).is_some();
+        nginx
+            .create_config_from_str("quic.conf", if quic { TEST_NGINX_QUIC_CONF } else { "" })
+            .expect(format!("Unable to create config file").as_str());
+        let proxy = cfg!(ngx_proxy_protocol_tlv);
+        nginx
+            .create_config_from_str(
+                "proxy_protocol.conf",
+                if proxy { TEST_NGINX_PROXY_CONF } else { "" },
+            )
+            .expect(format!("Unable to create config file").as_str());
+        nginx
+            .create_config_from_str("ban.conf", TEST_NGINX_BAN_CONF)
+            .expect(format!("Unable to create config file").as_str());
 
         // start nginx
         let output = nginx.restart().expect("Unable to restart NGINX");
@@ -132,8 +583,26 @@ mod tests {
 
         // test core
         let mut f: Result<(), (&str, Option<&str>, u32, Result<u32, Error>)> = Ok(());
-        for (url, header_host, code) in TEST_CURL_TUPLE {
-            let res: Result<u32, Error> = curl_test(url, header_host);
+        for &(url, header_host, code) in TEST_CURL_TUPLE
+            .iter()
+            .chain(TEST_CURL_RIGOR_TUPLE.iter())
+            .chain(TEST_CURL_RFC_TUPLE.iter())
+            .chain(TEST_CURL_CERT_TUPLE.iter())
+            .chain(TEST_CURL_SERVER_TUPLE.iter())
+            .chain(TEST_CURL_ALIAS_TUPLE.iter())
+            .chain(TEST_CURL_CANONICAL_TUPLE.iter())
+            .chain(TEST_CURL_IP_TUPLE.iter())
+            .chain(TEST_CURL_REJECT_TUPLE.iter())
+            .chain(TEST_CURL_REDIRECT_TUPLE.iter())
+            .chain(TEST_CURL_REPORT_TUPLE.iter())
+            .chain(TEST_CURL_VARIABLE_TUPLE.iter())
+            .chain(TEST_CURL_EXEMPT_TUPLE.iter())
+            .chain(TEST_CURL_SNI_SOURCE_TUPLE.iter())
+            .chain(TEST_CURL_STATUS_TUPLE.iter())
+            .chain(TEST_CURL_HTTP2_TUPLE.iter())
+            .chain(TEST_CURL_PORT_MAP_TUPLE.iter())
+        {
+            let res: Result<u32, Error> = curl_test(url, header_host, None);
             if let Ok(res_code) = res {
                 if res_code == code {
                     continue;
@@ -142,6 +611,104 @@ mod tests {
             f = Err((url, header_host, code, res));
             break;
         }
+        if f.is_ok() {
+            for &(url, header, code) in TEST_CURL_HEADER_TUPLE.iter() {
+                let res: Result<u32, Error> = curl_test(url, None, Some(header));
+                if let Ok(res_code) = res {
+                    if res_code == code {
+                        continue;
+                    }
+                }
+                f = Err((url, Some(header), code, res));
+                break;
+            }
+        }
+
+        if f.is_ok() {
+            f = curl_connection_test(&TEST_CURL_COALESCING_TUPLE);
+        }
+
+        if f.is_ok() && quic {
+            for &(url, header_host, code) in TEST_CURL_QUIC_TUPLE.iter() {
+                let res = curl_quic_test(url, header_host);
+                if let Ok(res_code) = res {
+                    if res_code == code {
+                        continue;
+                    }
+                }
+                f = Err((url, header_host, code, res));
+                break;
+            }
+        }
+
+        if f.is_ok() && std::env::var_os(TEST_NGHTTP_ENV).is_some() {
+            for &(url, headers, code) in TEST_NGHTTP_TUPLE.iter() {
+                let res = nghttp_test(url, headers);
+                if res == Some(code) {
+                    continue;
+                }
+                f = Err((url, Some(headers[0]), code, res.ok_or(Error::new(0))));
+                break;
+            }
+        }
+
+        if f.is_ok() {
+            for &(addr, sni, request, code) in TEST_RAW_TUPLE.iter() {
+                let res = raw_test(addr, sni, request);
+                if res == Some(code) {
+                    continue;
+                }
+                // reported as a curl failure, with the request as the header
+                f = Err((addr, Some(request), code, res.ok_or(Error::new(0))));
+                break;
+            }
+        }
+
+        if f.is_ok() && proxy {
+            for &(authority, dst_port, client_ssl, host, code) in TEST_PROXY_TUPLE.iter() {
+                let res = proxy_test("127.0.0.1:4443", authority, dst_port, client_ssl, host);
+                if res == Some(code) {
+                    continue;
+                }
+                f = Err(("127.0.0.1:4443", Some(host), code, res.ok_or(Error::new(0))));
+                break;
+            }
+        }
+
+        // last, as it restarts nginx with a low threshold
+        if f.is_ok() {
+            nginx
+                .create_config_from_str("ban.conf", TEST_NGINX_LOW_BAN_CONF)
+                .expect(format!("Unable to create config file").as_str());
+            let output = nginx.restart().expect("Unable to restart NGINX");
+            assert!(output.status.success());
+            std::thread::sleep(std::time::Duration::from_secs(5));
+            for &(host, code) in TEST_PROXY_BAN_TUPLE.iter() {
+                let res = proxy_test("127.0.0.1:4444", None, 4444, false, host);
+                if res == Some(code) {
+                    continue;
+                }
+                f = Err(("127.0.0.1:4444", Some(host), code, res.ok_or(Error::new(0))));
+                break;
+            }
+        }
+        if f.is_ok() {
+            // the banned address is listed, and the one of the other tests is not
+            let url = "http://localhost:8080/bans";
+            f = match curl_body(url) {
+                Ok((200, body))
+                    if body.contains(&format!("\"addr\":\"{}\"", TEST_BAN_ADDR))
+                        && !body.contains("127.0.0.1") =>
+                {
+                    Ok(())
+                }
+                Ok((code, body)) => {
+                    println!("bans: {}", body);
+                    Err((url, None, 200, Ok(code)))
+                }
+                Err(e) => Err((url, None, 200, Err(e))),
+            };
+        }
 
         //let test_result = TEST_CURL_TUPLE
         //    .map(|(url, header_host, code)| (url, header_host, code, curl_test(url, header_host)));
@@ -172,20 +739,173 @@ mod tests {
     }
 
     use curl::{
-        easy::{Easy, List},
+        easy::{Easy, HttpVersion, List},
         Error,
     };
-    fn curl_test(url: &str, header_host: Option<&str>) -> Result<u32, Error> {
+    fn curl_test(url: &str, header_host: Option<&str>, header: Option<&str>) -> Result<u32, Error> {
+        curl_perform(&mut Easy::new(), url, header_host, header)
+    }
+
+    fn curl_quic_test(url: &str, header_host: Option<&str>) -> Result<u32, Error> {
+        let mut handle = Easy::new();
+        handle.http_version(HttpVersion::V3)?;
+        curl_perform(&mut handle, url, header_host, None)
+    }
+
+    // the requests in turn on one handle, which reuses the connection
+    fn curl_connection_test<'a>(
+        requests: &[(&'a str, Option<&'a str>, u32)],
+    ) -> Result<(), (&'a str, Option<&'a str>, u32, Result<u32, Error>)> {
+        let mut handle = Easy::new();
+        for &(url, header_host, code) in requests {
+            let res = curl_perform(&mut handle, url, header_host, None);
+            if !matches!(res, Ok(res_code) if res_code == code) {
+                return Err((url, header_host, code, res));
+            }
+        }
+        Ok(())
+    }
+
+    // the status and the body of a plain request
+    fn curl_body(url: &str) -> Result<(u32, String), Error> {
+        let mut handle = Easy::new();
+        let mut body = Vec::new();
+        handle.url(url)?;
+        {
+            let mut transfer = handle.transfer();
+            transfer.write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer.perform()?;
+        }
+        Ok((
+            handle.response_code()?,
+            String::from_utf8_lossy(&body).into_owned(),
+        ))
+    }
+
+    fn curl_perform(
+        handle: &mut Easy,
+        url: &str,
+        header_host: Option<&str>,
+        header: Option<&str>,
+    ) -> Result<u32, Error> {
         let mut list = List::new();
+        let mut resolve = List::new();
+        for r in TEST_CURL_RESOLVE {
+            resolve.append(r)?;
+        }
+        handle.resolve(resolve)?;
         if let Some(hh) = header_host {
-            list.append(format!("Host: {}", hh).as_str())?;
+            if hh.is_empty() {
+                // a bare "Host:" makes curl drop the header, which only HTTP/1.0 allows
+                list.append("Host:")?;
+                handle.http_version(HttpVersion::V10)?;
+            } else {
+                list.append(format!("Host: {}", hh).as_str())?;
+            }
+        }
+        if let Some(header) = header {
+            list.append(header)?;
         }
-        let mut handle = Easy::new();
         handle.ssl_verify_peer(false)?;
         handle.ssl_verify_host(false)?;
         handle.url(url)?;
         handle.http_headers(list)?;
-        handle.perform()?;
+        match handle.perform() {
+            Err(e) if e.is_got_nothing() => return Ok(444),
+            r => r?,
+        }
         return Ok(handle.response_code()?);
     }
+
+    // the status of an HTTP/2 request by nghttp, with the headers added as they are
+    fn nghttp_test(url: &str, headers: &[&str]) -> Option<u32> {
+        use std::process::{Command, Stdio};
+        let mut command = Command::new("nghttp");
+        command.args(["-nv", url]);
+        for header in headers {
+            command.args(["-H", header]);
+        }
+        let output = command.stderr(Stdio::null()).output().ok()?;
+        let response = String::from_utf8_lossy(&output.stdout);
+        // "[  0.004] recv (stream_id=13) :status: 421"
+        let status = response
+            .lines()
+            .find_map(|line| line.split_once(") :status: "))?;
+        status.1.trim().parse().ok()
+    }
+
+    // the status of a raw request over TLS by openssl s_client, 444 for no response
+    fn raw_test(addr: &str, sni: &str, request: &str) -> Option<u32> {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+        let mut child = Command::new("openssl")
+            .args(["s_client", "-quiet", "-ign_eof", "-connect", addr])
+            .args(["-servername", sni])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut stdin = child.stdin.take()?;
+        write!(stdin, "{}Connection: close\r\n\r\n", request).ok()?;
+        drop(stdin);
+        let output = child.wait_with_output().ok()?;
+        let response = String::from_utf8_lossy(&output.stdout);
+        match response.lines().next() {
+            None => Some(444),
+            Some(status_line) => status_line.split(' ').nth(1)?.parse().ok(),
+        }
+    }
+
+    // the status of a plain request behind a PROXY protocol v2 header
+    // from 192.0.2.1:50000 to 127.0.0.1:dst_port, 444 for no response
+    fn proxy_test(
+        addr: &str,
+        authority: Option<&str>,
+        dst_port: u16,
+        client_ssl: bool,
+        host: &str,
+    ) -> Option<u32> {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        let mut tlvs = Vec::new();
+        if let Some(authority) = authority {
+            // PP2_TYPE_AUTHORITY
+            tlvs.push(0x02);
+            tlvs.extend_from_slice(&(authority.len() as u16).to_be_bytes());
+            tlvs.extend_from_slice(authority.as_bytes());
+        }
+        if client_ssl {
+            // PP2_TYPE_SSL: PP2_CLIENT_SSL, then a verify result of 0
+            tlvs.push(0x20);
+            tlvs.extend_from_slice(&5u16.to_be_bytes());
+            tlvs.extend_from_slice(&[0x01, 0, 0, 0, 0]);
+        }
+        let mut message = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        // v2 PROXY, TCP over IPv4
+        message.extend_from_slice(&[0x21, 0x11]);
+        message.extend_from_slice(&(12 + tlvs.len() as u16).to_be_bytes());
+        message.extend_from_slice(&[192, 0, 2, 1, 127, 0, 0, 1]);
+        message.extend_from_slice(&50000u16.to_be_bytes());
+        message.extend_from_slice(&dst_port.to_be_bytes());
+        message.extend_from_slice(&tlvs);
+        write!(
+            message,
+            "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            host
+        )
+        .ok()?;
+        let mut stream = TcpStream::connect(addr).ok()?;
+        stream.write_all(&message).ok()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).ok()?;
+        let response = String::from_utf8_lossy(&response);
+        match response.lines().next() {
+            None => Some(444),
+            Some(status_line) => status_line.split(' ').nth(1)?.parse().ok(),
+        }
+    }
 }